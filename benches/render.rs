@@ -0,0 +1,125 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ghost_resend_mailer::{
+    config::Config,
+    email,
+    ghost::{Member, Post, Settings},
+};
+use serde_json::json;
+
+const RECIPIENTS: usize = 1_000;
+
+fn config() -> Config {
+    for (key, value) in [
+        ("GHOST_URL", "https://blog.example.com"),
+        ("GHOST_ADMIN_ID", "bench"),
+        ("GHOST_ADMIN_SECRET", "00"),
+        ("WEBHOOK_SECRET", "bench-secret"),
+        ("RESEND_API_KEY", "re_bench"),
+        ("FROM_EMAIL", "blog@example.com"),
+        ("PUBLIC_URL", "https://mailer.example.com"),
+        ("UTM_PARAMS", "true"),
+    ] {
+        std::env::set_var(key, value);
+    }
+    Config::from_env().expect("bench config is complete")
+}
+
+fn post() -> Post {
+    let paragraph = r#"<p>Hi {first_name, "there"}, this paragraph has <a href="/related/">a relative link</a> and some <strong>formatting</strong> to chew through.</p>
+<figure class="kg-card kg-image-card"><img src="/content/images/2024/01/photo.jpg" srcset="/content/images/size/w600/2024/01/photo.jpg 600w, /content/images/size/w1600/2024/01/photo.jpg 1600w" class="kg-image" alt=""></figure>
+<div class="kg-card kg-callout-card kg-callout-card-blue"><div class="kg-callout-emoji">💡</div><div class="kg-callout-text">A callout worth reading.</div></div>
+"#;
+
+    serde_json::from_value(json!({
+        "id": "post-id",
+        "uuid": "post-uuid",
+        "title": "Benchmarking the renderer",
+        "slug": "benchmarking-the-renderer",
+        "html": paragraph.repeat(50),
+        "comment_id": "post-id",
+        "plaintext": "",
+        "feature_image": "/content/images/2024/01/feature.jpg",
+        "featured": false,
+        "status": "published",
+        "visibility": "public",
+        "created_at": "2024-01-01T00:00:00.000Z",
+        "updated_at": "2024-01-01T00:00:00.000Z",
+        "published_at": "2024-01-01T00:00:00.000Z",
+        "url": "https://blog.example.com/benchmarking-the-renderer/",
+        "excerpt": "How fast can we render a campaign?",
+        "primary_author": {
+            "name": "Author",
+            "profile_image": null,
+            "bio": null,
+            "url": "https://blog.example.com/author/author/"
+        },
+        "reading_time": 5,
+        "feature_image_alt": null,
+        "feature_image_caption": null
+    }))
+    .expect("bench post is valid")
+}
+
+fn members() -> Vec<Member> {
+    (0..RECIPIENTS)
+        .map(|i| {
+            serde_json::from_value(json!({
+                "id": format!("member-{}", i),
+                "uuid": format!("00000000-0000-0000-0000-{:012}", i),
+                "email": format!("member{}@example.com", i),
+                "name": format!("Member {}", i),
+                "status": "free",
+                "created_at": "2024-01-01T00:00:00.000Z",
+                "updated_at": "2024-01-01T00:00:00.000Z"
+            }))
+            .expect("bench member is valid")
+        })
+        .collect()
+}
+
+fn settings() -> Settings {
+    Settings {
+        title: "Example Blog".to_string(),
+        description: "A blog".to_string(),
+        accent_color: Some("#ff1a75".to_string()),
+        url: "https://blog.example.com".to_string(),
+        members_support_address: None,
+        timezone: "Etc/UTC".to_string(),
+    }
+}
+
+fn render_campaign(c: &mut Criterion) {
+    let config = config();
+    let post = post();
+    let members = members();
+    let settings = settings();
+
+    let mut group = c.benchmark_group("render_campaign");
+    group.throughput(Throughput::Elements(RECIPIENTS as u64));
+    group.sample_size(10);
+
+    // Rendering the whole document for every recipient
+    group.bench_function(BenchmarkId::new("format_email", RECIPIENTS), |b| {
+        b.iter(|| {
+            for member in &members {
+                let html = email::format_email(&post, member, &config, &settings).unwrap();
+                criterion::black_box(html);
+            }
+        })
+    });
+
+    // Rendering once and filling in each recipient
+    group.bench_function(BenchmarkId::new("compiled", RECIPIENTS), |b| {
+        b.iter(|| {
+            let compiled = email::compile_email(&post, &config, &settings).unwrap();
+            for member in &members {
+                criterion::black_box(compiled.personalize(member, &config));
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, render_campaign);
+criterion_main!(benches);
@@ -0,0 +1,467 @@
+use crate::{
+    analytics,
+    config::Config,
+    digest, ghost,
+    mailer::{self, DryRunReport},
+    preview,
+    queue::{Queue, SendOptions},
+    schedule,
+    state::AppState,
+    store::{CampaignStatus, CampaignSummary, Recipient, RecipientStatus, Store},
+    suppression::{self, Suppression, SuppressionKind, SuppressionReason},
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Routes of the admin API, all behind admin authentication.
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route(
+            "/suppressions",
+            get(list_suppressions)
+                .post(add_suppression)
+                .delete(remove_suppression),
+        )
+        .route("/suppressions/export", get(export_suppressions))
+        .route("/suppressions/import", post(import_suppressions))
+        .route("/campaigns/:id/stats", get(analytics::campaign_stats_json))
+        .route("/campaigns/:id/report", get(analytics::campaign_report))
+        .route(
+            "/members/:member/history",
+            get(analytics::member_history_json),
+        )
+        .route("/members/:member/report", get(analytics::member_report))
+        .route("/campaigns", get(list_campaigns))
+        .route("/campaigns/:id", get(get_campaign))
+        .route("/campaigns/:id/recipients", get(list_recipients))
+        .route("/campaigns/:id/cancel", post(cancel_campaign))
+        .route("/campaigns/:id/retry", post(retry_campaign))
+        .route("/campaigns/:id/dry-run", get(dry_run_report))
+        .route("/campaigns/:id/schedule", post(schedule_campaign))
+        .route("/posts/:post_id/send", post(send_post))
+        .route("/posts/:post_id/test", post(preview::handle_test_send))
+        .route("/preview/:post_id", get(preview::preview_post))
+        .route("/digest/preview", get(digest::preview_digest))
+        .route("/digest/send", post(digest::handle_send_digest))
+        .route("/queue", get(queue_status))
+        .route("/queue/pause", post(pause_queue))
+        .route("/queue/resume", post(resume_queue))
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+/// Rejects requests without `Authorization: Bearer {ADMIN_TOKEN}` or basic auth
+/// matching `ADMIN_USERNAME`/`ADMIN_PASSWORD`. The admin API is disabled entirely
+/// when neither is configured.
+async fn require_admin(
+    State(config): State<Config>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Response> {
+    if config.admin_token.is_none() && config.admin_password.is_none() {
+        tracing::warn!("Admin API request rejected, no admin credentials are configured");
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    let bearer_ok = match (authorization.strip_prefix("Bearer "), &config.admin_token) {
+        (Some(provided), Some(expected)) => {
+            constant_time_eq(provided.as_bytes(), expected.as_bytes())
+        }
+        _ => false,
+    };
+    let basic_ok = match (authorization.strip_prefix("Basic "), &config.admin_password) {
+        (Some(provided), Some(password)) => {
+            let expected = format!("{}:{}", config.admin_username, password);
+            STANDARD
+                .decode(provided.trim())
+                .is_ok_and(|provided| constant_time_eq(&provided, expected.as_bytes()))
+        }
+        _ => false,
+    };
+
+    if !bearer_ok && !basic_ok {
+        tracing::warn!("Admin API request with invalid credentials");
+        // Let browsers prompt for the password when basic auth is available
+        return Err(match config.admin_password {
+            Some(_) => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, r#"Basic realm="admin""#)],
+            )
+                .into_response(),
+            None => StatusCode::UNAUTHORIZED.into_response(),
+        });
+    }
+
+    Ok(next.run(request).await)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn bad_request(e: impl std::fmt::Display) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, e.to_string())
+}
+
+async fn list_suppressions(State(store): State<Store>) -> Json<Vec<Suppression>> {
+    Json(store.suppressions())
+}
+
+#[derive(Debug, Deserialize)]
+struct AddSuppression {
+    #[serde(rename = "type", default)]
+    kind: SuppressionKind,
+    value: String,
+    detail: Option<String>,
+}
+
+async fn add_suppression(
+    State(store): State<Store>,
+    Json(request): Json<AddSuppression>,
+) -> Result<(StatusCode, Json<Suppression>), (StatusCode, String)> {
+    let suppression = Suppression::new(
+        request.kind,
+        &request.value,
+        SuppressionReason::Manual,
+        request.detail,
+    )
+    .map_err(bad_request)?;
+
+    if !store.suppress(suppression.clone()).map_err(bad_request)? {
+        return Err((StatusCode::CONFLICT, "Already suppressed".to_string()));
+    }
+
+    tracing::info!(
+        "Added {} suppression for {}",
+        suppression.kind.as_str(),
+        suppression.value
+    );
+    skip_suppressed_recipients(&store)?;
+    Ok((StatusCode::CREATED, Json(suppression)))
+}
+
+#[derive(Debug, Deserialize)]
+struct RemoveSuppression {
+    #[serde(rename = "type", default)]
+    kind: SuppressionKind,
+    value: String,
+}
+
+async fn remove_suppression(
+    State(store): State<Store>,
+    Query(request): Query<RemoveSuppression>,
+) -> StatusCode {
+    if store.remove_suppression(request.kind, &request.value) {
+        tracing::info!(
+            "Removed {} suppression for {}",
+            request.kind.as_str(),
+            request.value
+        );
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn export_suppressions(State(store): State<Store>) -> Result<Response, (StatusCode, String)> {
+    let csv = suppression::export_csv(&store.suppressions()).map_err(|e| {
+        tracing::error!("Failed to export suppressions: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"suppressions.csv\"",
+            ),
+        ],
+        csv,
+    )
+        .into_response())
+}
+
+#[derive(Debug, Serialize)]
+struct ImportResult {
+    imported: usize,
+    skipped: usize,
+}
+
+async fn import_suppressions(
+    State(store): State<Store>,
+    body: Bytes,
+) -> Result<Json<ImportResult>, (StatusCode, String)> {
+    let suppressions = suppression::import_csv(&body).map_err(bad_request)?;
+
+    let total = suppressions.len();
+    let mut imported = 0;
+    for suppression in suppressions {
+        let value = suppression.value.clone();
+        let added = store
+            .suppress(suppression)
+            .map_err(|e| bad_request(format!("{}: {}", value, e)))?;
+        if added {
+            imported += 1;
+        }
+    }
+
+    tracing::info!("Imported {} of {} suppressions", imported, total);
+    skip_suppressed_recipients(&store)?;
+    Ok(Json(ImportResult {
+        imported,
+        skipped: total - imported,
+    }))
+}
+
+fn skip_suppressed_recipients(store: &Store) -> Result<(), (StatusCode, String)> {
+    let skipped = store.skip_suppressed_recipients().map_err(|e| {
+        tracing::error!("Failed to skip suppressed recipients: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+    if skipped > 0 {
+        tracing::info!("Skipped {} pending recipients now suppressed", skipped);
+    }
+    Ok(())
+}
+
+fn load_summary(store: &Store, campaign_id: &str) -> Result<CampaignSummary, StatusCode> {
+    let campaign = store.campaign(campaign_id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(store.campaign_summary(campaign))
+}
+
+async fn list_campaigns(State(store): State<Store>) -> Json<Vec<CampaignSummary>> {
+    Json(
+        store
+            .campaigns()
+            .into_iter()
+            .map(|campaign| store.campaign_summary(campaign))
+            .collect(),
+    )
+}
+
+async fn get_campaign(
+    State(store): State<Store>,
+    Path(campaign_id): Path<String>,
+) -> Result<Json<CampaignSummary>, StatusCode> {
+    load_summary(&store, &campaign_id).map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+struct RecipientFilter {
+    /// `pending`, `sent`, `failed` or `skipped`
+    status: Option<String>,
+}
+
+async fn list_recipients(
+    State(store): State<Store>,
+    Path(campaign_id): Path<String>,
+    Query(filter): Query<RecipientFilter>,
+) -> Result<Json<Vec<Recipient>>, StatusCode> {
+    store.campaign(&campaign_id).ok_or(StatusCode::NOT_FOUND)?;
+
+    let recipients = store
+        .recipients(&campaign_id)
+        .into_iter()
+        .filter(|recipient| {
+            let status = match recipient.status {
+                RecipientStatus::Pending => "pending",
+                RecipientStatus::Sent { .. } => "sent",
+                RecipientStatus::Failed { .. } => "failed",
+                RecipientStatus::Skipped { .. } => "skipped",
+            };
+            filter
+                .status
+                .as_deref()
+                .map_or(true, |wanted| wanted == status)
+        })
+        .collect();
+
+    Ok(Json(recipients))
+}
+
+/// Stops a campaign before its next batch. Recipients that weren't sent to yet
+/// are marked as skipped.
+async fn cancel_campaign(
+    State(store): State<Store>,
+    Path(campaign_id): Path<String>,
+) -> Result<Json<CampaignSummary>, (StatusCode, String)> {
+    let campaign = store
+        .campaign(&campaign_id)
+        .ok_or((StatusCode::NOT_FOUND, "Campaign not found".to_string()))?;
+    if !campaign.is_active() {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Campaign is already {:?}", campaign.status).to_lowercase(),
+        ));
+    }
+
+    store.set_campaign_status(&campaign_id, CampaignStatus::Cancelled);
+    store.update_recipients(&campaign_id, |recipient| {
+        (recipient.status == RecipientStatus::Pending).then(|| RecipientStatus::Skipped {
+            reason: "cancelled".to_string(),
+        })
+    });
+
+    tracing::info!("Cancelled campaign {}", campaign_id);
+    load_summary(&store, &campaign_id)
+        .map(Json)
+        .map_err(|status| (status, String::new()))
+}
+
+/// Queues the failed recipients of a campaign again.
+async fn retry_campaign(
+    State(store): State<Store>,
+    State(queue): State<Queue>,
+    Path(campaign_id): Path<String>,
+) -> Result<Json<CampaignSummary>, StatusCode> {
+    store.campaign(&campaign_id).ok_or(StatusCode::NOT_FOUND)?;
+
+    let retried = store.update_recipients(&campaign_id, |recipient| {
+        matches!(recipient.status, RecipientStatus::Failed { .. })
+            .then_some(RecipientStatus::Pending)
+    });
+    if retried > 0 {
+        store.set_campaign_status(&campaign_id, CampaignStatus::Queued);
+        queue.wake();
+    }
+
+    tracing::info!(
+        "Retrying {} failed recipients of campaign {}",
+        retried,
+        campaign_id
+    );
+    load_summary(&store, &campaign_id).map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+struct SendParams {
+    /// Go through the whole campaign without sending anything
+    #[serde(default)]
+    dry_run: bool,
+    /// RFC 3339 time or `now`, overrides the post's tag and the configured default
+    #[serde(default, deserialize_with = "schedule::deserialize_send_at")]
+    send_at: Option<DateTime<Utc>>,
+}
+
+/// Sends a post to the list again, even if it was sent before. With `DRY_RUN`
+/// on every send is a dry run, whatever the request asks for.
+async fn send_post(
+    State(config): State<Config>,
+    State(store): State<Store>,
+    State(queue): State<Queue>,
+    Path(post_id): Path<String>,
+    Query(params): Query<SendParams>,
+) -> Result<(StatusCode, Json<CampaignSummary>), StatusCode> {
+    let post = ghost::fetch_post(&config, &post_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch post {}: {}", post_id, e);
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    let options = SendOptions {
+        dry_run: config.dry_run || params.dry_run,
+        send_at: params.send_at,
+        force: true,
+    };
+    let campaign = queue.enqueue_post(post, options).await.map_err(|e| {
+        tracing::error!("Failed to queue post {}: {}", post_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    tracing::info!("Force-sending post {} as campaign {}", post_id, campaign.id);
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(load_summary(&store, &campaign.id)?),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct ScheduleRequest {
+    #[serde(deserialize_with = "schedule::deserialize_send_at")]
+    send_at: Option<DateTime<Utc>>,
+}
+
+/// Moves the send time of a campaign that is still held in the queue.
+async fn schedule_campaign(
+    State(store): State<Store>,
+    State(queue): State<Queue>,
+    Path(campaign_id): Path<String>,
+    Json(request): Json<ScheduleRequest>,
+) -> Result<Json<CampaignSummary>, (StatusCode, String)> {
+    let campaign = store
+        .campaign(&campaign_id)
+        .ok_or((StatusCode::NOT_FOUND, "Campaign not found".to_string()))?;
+    if !store.set_send_at(&campaign_id, request.send_at) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Campaign is already {:?}", campaign.status).to_lowercase(),
+        ));
+    }
+    queue.wake();
+
+    tracing::info!(
+        "Scheduled campaign {} for {:?}",
+        campaign_id,
+        request.send_at
+    );
+    load_summary(&store, &campaign_id)
+        .map(Json)
+        .map_err(|status| (status, String::new()))
+}
+
+/// Who a dry run campaign would have sent what, and in how many batches.
+async fn dry_run_report(
+    State(store): State<Store>,
+    Path(campaign_id): Path<String>,
+) -> Result<Json<DryRunReport>, StatusCode> {
+    mailer::dry_run_report(&store, &campaign_id)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug, Serialize)]
+struct QueueStatus {
+    paused: bool,
+    /// Campaigns queued or being sent, oldest first
+    active: Vec<CampaignSummary>,
+}
+
+async fn queue_status(State(store): State<Store>) -> Json<QueueStatus> {
+    let mut active: Vec<CampaignSummary> = store
+        .campaigns()
+        .into_iter()
+        .filter(|campaign| campaign.is_active())
+        .map(|campaign| store.campaign_summary(campaign))
+        .collect();
+    active.reverse();
+
+    Json(QueueStatus {
+        paused: store.queue_paused(),
+        active,
+    })
+}
+
+async fn pause_queue(State(store): State<Store>, State(queue): State<Queue>) -> Json<QueueStatus> {
+    queue.pause();
+    queue_status(State(store)).await
+}
+
+async fn resume_queue(State(store): State<Store>, State(queue): State<Queue>) -> Json<QueueStatus> {
+    queue.resume();
+    queue_status(State(store)).await
+}
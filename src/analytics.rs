@@ -0,0 +1,529 @@
+use crate::{
+    email::escape_html,
+    page::page,
+    store::{EventKind, SendRecord, Store},
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Html,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+// Links shown in the campaign report, most clicked first
+const TOP_LINKS: usize = 10;
+
+const STYLE: &str = "body { max-width: 860px; }
+        table { border-collapse: collapse; width: 100%; margin-bottom: 30px; }
+        th, td { text-align: left; padding: 6px 10px; border-bottom: 1px solid #e5eff5; word-break: break-all; }";
+
+/// How one send fared, combining Resend's events with our own tracking.
+#[derive(Debug, Default, Serialize)]
+pub struct SendOutcome {
+    pub delivered: bool,
+    /// Opened by a person, machine opens (Apple MPP, scanners) don't count
+    pub opened: bool,
+    pub clicked: bool,
+    pub bounced: bool,
+    pub complained: bool,
+    /// Clicked links, once per click
+    pub clicks: Vec<String>,
+}
+
+impl SendOutcome {
+    pub fn of(send: &SendRecord) -> Self {
+        let has = |kind: EventKind| send.events.iter().any(|event| event.kind == kind);
+
+        // With both click trackers on, Resend reports the clicks on our redirect
+        // again, so its events only count for sends we have no clicks for
+        let clicks: Vec<String> = if send.clicks.is_empty() {
+            send.events
+                .iter()
+                .filter(|event| event.kind == EventKind::Clicked)
+                .filter_map(|event| event.detail.clone())
+                .collect()
+        } else {
+            send.clicks.iter().map(|click| click.url.clone()).collect()
+        };
+        let clicked = !clicks.is_empty() || has(EventKind::Clicked);
+
+        SendOutcome {
+            // Bounces can follow a delivery, e.g. an out of office turned hard bounce
+            delivered: has(EventKind::Delivered) && !has(EventKind::Bounced),
+            // Clicking a link means the email was opened, even with images off
+            opened: clicked
+                || has(EventKind::Opened)
+                || send.opens.iter().any(|open| open.kind.is_human()),
+            clicked,
+            bounced: has(EventKind::Bounced),
+            complained: has(EventKind::Complained),
+            clicks,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LinkStats {
+    pub url: String,
+    pub clicks: usize,
+    pub unique_clicks: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Bounce {
+    pub email: String,
+    pub at: DateTime<Utc>,
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CampaignStats {
+    pub campaign_id: String,
+    pub post_id: String,
+    pub post_title: String,
+    pub created_at: DateTime<Utc>,
+    pub sent: usize,
+    pub delivered: usize,
+    pub opened: usize,
+    /// Sends only opened by Apple Mail Privacy Protection or scanners
+    pub machine_opened: usize,
+    pub clicked: usize,
+    pub bounced: usize,
+    pub complained: usize,
+    /// Opens over delivered emails, or over sent ones without delivery events
+    pub open_rate: f64,
+    pub click_rate: f64,
+    pub top_links: Vec<LinkStats>,
+    pub bounces: Vec<Bounce>,
+}
+
+/// Aggregates the sends of a campaign, `None` if the campaign doesn't exist.
+pub fn campaign_stats(store: &Store, campaign_id: &str) -> Option<CampaignStats> {
+    store.read(|data| {
+        let campaign = data.campaigns.get(campaign_id)?;
+        let sends: Vec<&SendRecord> = data
+            .sends
+            .values()
+            .filter(|send| send.campaign_id == campaign_id)
+            .collect();
+
+        let mut stats = CampaignStats {
+            campaign_id: campaign.id.clone(),
+            post_id: campaign.post_id.clone(),
+            post_title: campaign.post_title.clone(),
+            created_at: campaign.created_at,
+            sent: sends.len(),
+            delivered: 0,
+            opened: 0,
+            machine_opened: 0,
+            clicked: 0,
+            bounced: 0,
+            complained: 0,
+            open_rate: 0.0,
+            click_rate: 0.0,
+            top_links: Vec::new(),
+            bounces: Vec::new(),
+        };
+        let mut links: HashMap<String, (usize, HashSet<&str>)> = HashMap::new();
+
+        for send in &sends {
+            let outcome = SendOutcome::of(send);
+            stats.delivered += outcome.delivered as usize;
+            stats.opened += outcome.opened as usize;
+            stats.machine_opened += (!outcome.opened && !send.opens.is_empty()) as usize;
+            stats.clicked += outcome.clicked as usize;
+            stats.bounced += outcome.bounced as usize;
+            stats.complained += outcome.complained as usize;
+
+            for url in outcome.clicks {
+                let (clicks, senders) = links.entry(url).or_default();
+                *clicks += 1;
+                senders.insert(&send.email_id);
+            }
+
+            if let Some(event) = send
+                .events
+                .iter()
+                .find(|event| event.kind == EventKind::Bounced)
+            {
+                stats.bounces.push(Bounce {
+                    email: send.email.clone(),
+                    at: event.at,
+                    detail: event.detail.clone(),
+                });
+            }
+        }
+
+        let base = if stats.delivered > 0 {
+            stats.delivered
+        } else {
+            stats.sent
+        };
+        if base > 0 {
+            stats.open_rate = stats.opened as f64 / base as f64;
+            stats.click_rate = stats.clicked as f64 / base as f64;
+        }
+
+        stats.top_links = links
+            .into_iter()
+            .map(|(url, (clicks, senders))| LinkStats {
+                url,
+                clicks,
+                unique_clicks: senders.len(),
+            })
+            .collect();
+        stats.top_links.sort_by(|a, b| {
+            b.unique_clicks
+                .cmp(&a.unique_clicks)
+                .then(a.url.cmp(&b.url))
+        });
+        stats.top_links.truncate(TOP_LINKS);
+        stats.bounces.sort_by_key(|bounce| bounce.at);
+
+        Some(stats)
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemberSend {
+    pub campaign_id: String,
+    pub post_title: String,
+    pub email: String,
+    pub sent_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub outcome: SendOutcome,
+}
+
+/// Every send to a member, found by uuid or email address, newest first.
+pub fn member_history(store: &Store, member: &str) -> Vec<MemberSend> {
+    let mut history: Vec<MemberSend> = store.read(|data| {
+        data.sends
+            .values()
+            .filter(|send| send.member_uuid == member || send.email.eq_ignore_ascii_case(member))
+            .map(|send| MemberSend {
+                campaign_id: send.campaign_id.clone(),
+                post_title: data
+                    .campaigns
+                    .get(&send.campaign_id)
+                    .map(|campaign| campaign.post_title.clone())
+                    .unwrap_or_default(),
+                email: send.email.clone(),
+                sent_at: send.sent_at,
+                outcome: SendOutcome::of(send),
+            })
+            .collect()
+    });
+    history.sort_by(|a, b| b.sent_at.cmp(&a.sent_at));
+    history
+}
+
+pub async fn campaign_stats_json(
+    State(store): State<Store>,
+    Path(campaign_id): Path<String>,
+) -> Result<Json<CampaignStats>, StatusCode> {
+    campaign_stats(&store, &campaign_id)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Campaign performance as a page editors can open in the browser.
+pub async fn campaign_report(
+    State(store): State<Store>,
+    Path(campaign_id): Path<String>,
+) -> Result<Html<String>, StatusCode> {
+    let stats = campaign_stats(&store, &campaign_id).ok_or(StatusCode::NOT_FOUND)?;
+
+    let summary = [
+        ("Sent", stats.sent.to_string()),
+        ("Delivered", stats.delivered.to_string()),
+        (
+            "Opened",
+            format!("{} ({})", stats.opened, percent(stats.open_rate)),
+        ),
+        ("Machine opens only", stats.machine_opened.to_string()),
+        (
+            "Clicked",
+            format!("{} ({})", stats.clicked, percent(stats.click_rate)),
+        ),
+        ("Bounced", stats.bounced.to_string()),
+        ("Complaints", stats.complained.to_string()),
+    ]
+    .iter()
+    .map(|(label, value)| format!("<tr><th>{}</th><td>{}</td></tr>", label, value))
+    .collect::<Vec<_>>()
+    .join("");
+
+    let links = stats
+        .top_links
+        .iter()
+        .map(|link| {
+            format!(
+                r#"<tr><td><a href="{url}">{url}</a></td><td>{}</td><td>{}</td></tr>"#,
+                link.unique_clicks,
+                link.clicks,
+                url = escape_html(&link.url)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("");
+
+    let bounces = stats
+        .bounces
+        .iter()
+        .map(|bounce| {
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(&bounce.email),
+                bounce.at.format("%Y-%m-%d %H:%M"),
+                escape_html(bounce.detail.as_deref().unwrap_or(""))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("");
+
+    Ok(Html(page(
+        &stats.post_title,
+        STYLE,
+        &format!(
+            r#"<p>Sent {}</p>
+    <table>{}</table>
+    <h2>Top links</h2>
+    <table><tr><th>Link</th><th>Unique clicks</th><th>Clicks</th></tr>{}</table>
+    <h2>Bounces</h2>
+    <table><tr><th>Email</th><th>At</th><th>Reason</th></tr>{}</table>"#,
+            stats.created_at.format("%Y-%m-%d %H:%M UTC"),
+            summary,
+            links,
+            bounces
+        ),
+    )))
+}
+
+pub async fn member_history_json(
+    State(store): State<Store>,
+    Path(member): Path<String>,
+) -> Json<Vec<MemberSend>> {
+    Json(member_history(&store, &member))
+}
+
+/// A member's history as a page editors can open in the browser.
+pub async fn member_report(
+    State(store): State<Store>,
+    Path(member): Path<String>,
+) -> Result<Html<String>, StatusCode> {
+    let history = member_history(&store, &member);
+    if history.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let yes_no = |value: bool| if value { "Yes" } else { "" };
+    let rows = history
+        .iter()
+        .map(|send| {
+            format!(
+                r#"<tr><td><a href="../../campaigns/{}/report">{}</a></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>"#,
+                send.campaign_id,
+                escape_html(&send.post_title),
+                send.sent_at.format("%Y-%m-%d %H:%M"),
+                yes_no(send.outcome.delivered),
+                yes_no(send.outcome.opened),
+                yes_no(send.outcome.clicked),
+                yes_no(send.outcome.bounced || send.outcome.complained)
+            )
+        })
+        .collect::<Vec<_>>()
+    .join("");
+
+    Ok(Html(page(
+        &history[0].email,
+        STYLE,
+        &format!(
+            "<table><tr><th>Post</th><th>Sent</th><th>Delivered</th><th>Opened</th><th>Clicked</th><th>Bounced</th></tr>{}</table>",
+            rows
+        ),
+    )))
+}
+
+fn percent(rate: f64) -> String {
+    format!("{:.1}%", rate * 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{Campaign, CampaignStatus, Click, DeliveryEvent, Open, OpenKind};
+
+    const CAMPAIGN_ID: &str = "campaign";
+
+    fn send(id: &str) -> SendRecord {
+        SendRecord {
+            email_id: id.to_string(),
+            campaign_id: CAMPAIGN_ID.to_string(),
+            member_id: format!("member-{}", id),
+            member_uuid: format!("uuid-{}", id),
+            email: format!("{}@example.com", id),
+            sent_at: Utc::now(),
+            events: Vec::new(),
+            opens: Vec::new(),
+            clicks: Vec::new(),
+        }
+    }
+
+    fn event(kind: EventKind, detail: Option<&str>) -> DeliveryEvent {
+        DeliveryEvent {
+            kind,
+            at: Utc::now(),
+            detail: detail.map(|d| d.to_string()),
+        }
+    }
+
+    fn click(url: &str) -> Click {
+        Click {
+            url: url.to_string(),
+            at: Utc::now(),
+        }
+    }
+
+    fn open(kind: OpenKind) -> Open {
+        Open {
+            kind,
+            at: Utc::now(),
+        }
+    }
+
+    fn stats(sends: Vec<SendRecord>) -> CampaignStats {
+        let dir =
+            std::env::temp_dir().join(format!("grm-analytics-{}", uuid::Uuid::new_v4().simple()));
+        let store = Store::open(&dir).unwrap();
+        store.insert_campaign(
+            Campaign {
+                id: CAMPAIGN_ID.to_string(),
+                post_id: "post".to_string(),
+                post_title: "Post".to_string(),
+                created_at: Utc::now(),
+                status: CampaignStatus::Completed,
+                post: None,
+                dry_run: false,
+                send_at: None,
+                digest: None,
+                welcome: false,
+                failures: 0,
+                retry_at: None,
+                finished_at: None,
+            },
+            Vec::new(),
+        );
+        store.record_sends(sends);
+        campaign_stats(&store, CAMPAIGN_ID).unwrap()
+    }
+
+    #[test]
+    fn counts_own_clicks_over_resend_clicks() {
+        let mut send = send("a");
+        send.clicks = vec![click("https://a.example/"), click("https://a.example/")];
+        send.events = vec![event(EventKind::Clicked, Some("https://a.example/"))];
+
+        let outcome = SendOutcome::of(&send);
+        assert!(outcome.clicked);
+        assert_eq!(outcome.clicks, ["https://a.example/", "https://a.example/"]);
+    }
+
+    #[test]
+    fn falls_back_to_resend_clicks() {
+        let mut send = send("a");
+        send.events = vec![
+            event(EventKind::Clicked, Some("https://b.example/")),
+            event(EventKind::Clicked, None),
+        ];
+
+        let outcome = SendOutcome::of(&send);
+        assert!(outcome.clicked);
+        assert!(outcome.opened);
+        assert_eq!(outcome.clicks, ["https://b.example/"]);
+    }
+
+    #[test]
+    fn counts_clicks_and_human_opens_as_opened() {
+        let mut clicked = send("a");
+        clicked.clicks = vec![click("https://a.example/")];
+        assert!(SendOutcome::of(&clicked).opened);
+
+        let mut direct = send("b");
+        direct.opens = vec![open(OpenKind::AppleMpp), open(OpenKind::GmailProxy)];
+        assert!(SendOutcome::of(&direct).opened);
+
+        let mut machine = send("c");
+        machine.opens = vec![open(OpenKind::AppleMpp), open(OpenKind::Bot)];
+        let outcome = SendOutcome::of(&machine);
+        assert!(!outcome.opened);
+        assert!(!outcome.clicked);
+
+        let mut resend = send("d");
+        resend.events = vec![event(EventKind::Opened, None)];
+        assert!(SendOutcome::of(&resend).opened);
+    }
+
+    #[test]
+    fn bounces_cancel_deliveries() {
+        let mut send = send("a");
+        send.events = vec![
+            event(EventKind::Delivered, None),
+            event(EventKind::Bounced, Some("Mailbox full")),
+        ];
+        let outcome = SendOutcome::of(&send);
+        assert!(!outcome.delivered);
+        assert!(outcome.bounced);
+    }
+
+    #[test]
+    fn rates_are_over_delivered_sends() {
+        let mut sends: Vec<SendRecord> = (0..4).map(|i| send(&i.to_string())).collect();
+        for send in &mut sends {
+            send.events.push(event(EventKind::Delivered, None));
+        }
+        sends[0].opens = vec![open(OpenKind::Direct)];
+        sends[1].opens = vec![open(OpenKind::AppleMpp)];
+        sends[2].clicks = vec![click("https://a.example/"), click("https://a.example/")];
+        sends[2]
+            .events
+            .push(event(EventKind::Clicked, Some("https://a.example/")));
+        sends[3].events = vec![event(EventKind::Bounced, Some("No such user"))];
+
+        let stats = stats(sends);
+        assert_eq!(stats.sent, 4);
+        assert_eq!(stats.delivered, 3);
+        assert_eq!(stats.opened, 2);
+        assert_eq!(stats.machine_opened, 1);
+        assert_eq!(stats.clicked, 1);
+        assert_eq!(stats.bounced, 1);
+        assert_eq!(stats.open_rate, 2.0 / 3.0);
+        assert_eq!(stats.click_rate, 1.0 / 3.0);
+        assert_eq!(stats.top_links.len(), 1);
+        assert_eq!(stats.top_links[0].clicks, 2);
+        assert_eq!(stats.top_links[0].unique_clicks, 1);
+        assert_eq!(stats.bounces[0].email, "3@example.com");
+    }
+
+    #[test]
+    fn rates_fall_back_to_sent_without_delivery_events() {
+        let mut sends: Vec<SendRecord> = (0..4).map(|i| send(&i.to_string())).collect();
+        sends[0].opens = vec![open(OpenKind::Direct)];
+        sends[1].clicks = vec![click("https://a.example/")];
+
+        let stats = stats(sends);
+        assert_eq!(stats.delivered, 0);
+        assert_eq!(stats.open_rate, 0.5);
+        assert_eq!(stats.click_rate, 0.25);
+    }
+
+    #[test]
+    fn rates_are_zero_without_sends() {
+        let stats = stats(Vec::new());
+        assert_eq!(stats.sent, 0);
+        assert_eq!(stats.open_rate, 0.0);
+        assert_eq!(stats.click_rate, 0.0);
+    }
+}
@@ -0,0 +1,457 @@
+use crate::{
+    analytics,
+    config::{Config, DigestAudience},
+    digest, email, ghost,
+    mailer::{self, DryRunReport},
+    preview::sample_member,
+    queue::{Queue, SendOptions},
+    store::{Campaign, CampaignStatus, CampaignSummary, RecipientStatus, Store},
+};
+use anyhow::anyhow;
+use resend_rs::Resend;
+use std::path::Path;
+
+/// Opens the store for a command that changes it. The server keeps the store in
+/// memory and would overwrite those changes, so this refuses while it runs.
+pub fn claim_store(config: &Config) -> anyhow::Result<Store> {
+    let store = Store::open(&config.data_dir)?;
+    if !store.claim()? {
+        return Err(anyhow!(
+            "{} is in use by process {}, stop it or use the admin API instead",
+            config.data_dir.display(),
+            store.owner().unwrap_or_default()
+        ));
+    }
+    store.spawn_flush_task();
+    Ok(store)
+}
+
+/// Writes out a store opened with [`claim_store`] and gives it up.
+pub fn release_store(store: &Store) -> anyhow::Result<()> {
+    let result = store.flush();
+    store.release();
+    result
+}
+
+/// Sends a post to every subscriber and waits until the campaign is done, unless
+/// it is held for later, which leaves it to the server. Dry runs print every
+/// email that would have been sent instead.
+pub async fn send(
+    config: &Config,
+    post_id: &str,
+    force: bool,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let dry_run = config.dry_run || dry_run;
+    let store = claim_store(config)?;
+    let result = send_post(config, &store, post_id, force, dry_run).await;
+    release_store(&store)?;
+
+    let campaign = result?;
+    if let Some(report) = mailer::dry_run_report(&store, &campaign.id) {
+        print_dry_run(&report);
+    }
+    print_summary(&store.campaign_summary(campaign));
+    Ok(())
+}
+
+async fn send_post(
+    config: &Config,
+    store: &Store,
+    post_id: &str,
+    force: bool,
+    dry_run: bool,
+) -> anyhow::Result<Campaign> {
+    if let Some(campaign) = store.campaign_for_post(post_id, dry_run).filter(|_| !force) {
+        return Err(anyhow!(
+            "Post {} was already sent as campaign {}, use --force to send it again",
+            post_id,
+            campaign.id
+        ));
+    }
+
+    let post = ghost::fetch_post(config, post_id).await?;
+    let queue = Queue::new(config.clone(), store.clone());
+    let options = SendOptions {
+        dry_run,
+        force,
+        ..SendOptions::default()
+    };
+    let campaign = queue.enqueue_post(post, options).await?;
+    queue.run_until_idle(&campaign.id).await?;
+
+    store
+        .campaign(&campaign.id)
+        .ok_or_else(|| anyhow!("Campaign {} disappeared", campaign.id))
+}
+
+/// Renders a post for a member, or the sample member, to a file or stdout.
+pub async fn preview(
+    config: &Config,
+    post_id: &str,
+    member_id: Option<&str>,
+    text: bool,
+    out: Option<&Path>,
+) -> anyhow::Result<()> {
+    let post = ghost::fetch_post(config, post_id).await?;
+    let settings = ghost::fetch_settings(config).await?;
+    let member = match member_id {
+        Some(member_id) => ghost::fetch_member(config, member_id).await?,
+        None => sample_member(),
+    };
+
+    let mut rendered = email::format_email(&post, &member, config, &settings)?;
+    if text {
+        rendered = email::html_to_text(&rendered)?;
+    }
+
+    match out {
+        Some(path) => {
+            std::fs::write(path, rendered)?;
+            println!("Wrote preview of \"{}\" to {}", post.title, path.display());
+        }
+        None => println!("{}", rendered),
+    }
+    Ok(())
+}
+
+/// Sends the digest of posts published since the last one and waits until it
+/// is sent. Does nothing when there are no new posts.
+pub async fn send_digest(config: &Config, dry_run: bool) -> anyhow::Result<()> {
+    if config.digest.is_none() {
+        return Err(anyhow!("Digests are not configured, set DIGEST_SCHEDULE"));
+    }
+
+    let options = SendOptions {
+        dry_run: config.dry_run || dry_run,
+        ..SendOptions::default()
+    };
+    let store = claim_store(config)?;
+    let queue = Queue::new(config.clone(), store.clone());
+    let result = match digest::send_digest(config, &store, &queue, options).await {
+        Ok(Some(campaign)) => queue
+            .run_until_idle(&campaign.id)
+            .await
+            .map(|_| Some(campaign)),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+    release_store(&store)?;
+
+    let Some(campaign) = result? else {
+        println!("No posts published since the last digest");
+        return Ok(());
+    };
+    let campaign = store
+        .campaign(&campaign.id)
+        .ok_or_else(|| anyhow!("Campaign {} disappeared", campaign.id))?;
+    if let Some(report) = mailer::dry_run_report(&store, &campaign.id) {
+        print_dry_run(&report);
+    }
+    print_summary(&store.campaign_summary(campaign));
+    Ok(())
+}
+
+/// Renders the next digest for the sample member to a file or stdout.
+pub async fn preview_digest(config: &Config, out: Option<&Path>) -> anyhow::Result<()> {
+    let store = Store::open(&config.data_dir)?;
+    let rendered = digest::render_next_digest(config, &store).await?;
+
+    match out {
+        Some(path) => {
+            std::fs::write(path, rendered)?;
+            println!("Wrote digest preview to {}", path.display());
+        }
+        None => println!("{}", rendered),
+    }
+    Ok(())
+}
+
+/// Prints members matching a Ghost filter, one per line.
+pub async fn list_members(config: &Config, filter: Option<&str>) -> anyhow::Result<()> {
+    for member in ghost::fetch_members(config, filter).await? {
+        println!(
+            "{}\t{}\t{}\t{}",
+            member.id,
+            member.email,
+            member.name.as_deref().unwrap_or(""),
+            member.status
+        );
+    }
+    Ok(())
+}
+
+pub fn list_campaigns(config: &Config) -> anyhow::Result<()> {
+    let store = Store::open(&config.data_dir)?;
+    for campaign in store.campaigns() {
+        let summary = store.campaign_summary(campaign);
+        println!(
+            "{}\t{}\t{}{}{}\t{}/{}\t{}",
+            summary.id,
+            summary.created_at.format("%Y-%m-%d %H:%M"),
+            status_name(summary.status),
+            if summary.dry_run { " (dry run)" } else { "" },
+            if summary.welcome { " (welcome)" } else { "" },
+            summary.sent,
+            summary.recipients,
+            summary.post_title
+        );
+    }
+    Ok(())
+}
+
+/// Prints a campaign's progress and how its emails performed.
+pub fn show_campaign(config: &Config, campaign_id: &str) -> anyhow::Result<()> {
+    let store = Store::open(&config.data_dir)?;
+    let campaign = store
+        .campaign(campaign_id)
+        .ok_or_else(|| anyhow!("Campaign {} not found", campaign_id))?;
+    print_summary(&store.campaign_summary(campaign));
+
+    if let Some(report) = mailer::dry_run_report(&store, campaign_id) {
+        print_dry_run(&report);
+        return Ok(());
+    }
+
+    if let Some(stats) = analytics::campaign_stats(&store, campaign_id) {
+        println!("Delivered:  {}", stats.delivered);
+        println!(
+            "Opened:     {} ({:.1}%)",
+            stats.opened,
+            stats.open_rate * 100.0
+        );
+        println!(
+            "Clicked:    {} ({:.1}%)",
+            stats.clicked,
+            stats.click_rate * 100.0
+        );
+        println!("Bounced:    {}", stats.bounced);
+        println!("Complaints: {}", stats.complained);
+        for link in stats.top_links {
+            println!("  {}\t{}", link.unique_clicks, link.url);
+        }
+    }
+    Ok(())
+}
+
+/// Unpauses the queue and sends whatever it has left. A cancelled campaign
+/// passed by ID is queued again for the recipients it never reached.
+pub async fn resume_campaigns(config: &Config, campaign_id: Option<&str>) -> anyhow::Result<()> {
+    let store = claim_store(config)?;
+    let result = resume(config, &store, campaign_id).await;
+    release_store(&store)?;
+    for campaign in result? {
+        print_summary(&store.campaign_summary(campaign));
+    }
+    Ok(())
+}
+
+async fn resume(
+    config: &Config,
+    store: &Store,
+    campaign_id: Option<&str>,
+) -> anyhow::Result<Vec<Campaign>> {
+    if let Some(campaign_id) = campaign_id {
+        let campaign = store
+            .campaign(campaign_id)
+            .ok_or_else(|| anyhow!("Campaign {} not found", campaign_id))?;
+        match campaign.status {
+            CampaignStatus::Cancelled => {
+                store.update_recipients(campaign_id, |recipient| match &recipient.status {
+                    RecipientStatus::Skipped { reason } if reason == "cancelled" => {
+                        Some(RecipientStatus::Pending)
+                    }
+                    _ => None,
+                });
+                store.set_campaign_status(campaign_id, CampaignStatus::Queued);
+            }
+            CampaignStatus::Completed => {
+                return Err(anyhow!("Campaign {} is already completed", campaign_id));
+            }
+            CampaignStatus::Queued | CampaignStatus::Sending => {}
+        }
+    }
+
+    let active: Vec<String> = store
+        .campaigns()
+        .into_iter()
+        .filter(|campaign| campaign.is_active())
+        .map(|campaign| campaign.id)
+        .collect();
+
+    let queue = Queue::new(config.clone(), store.clone());
+    queue.resume();
+    queue.run_all_until_idle().await?;
+
+    Ok(active
+        .iter()
+        .filter_map(|campaign_id| store.campaign(campaign_id))
+        .collect())
+}
+
+/// Checks that Ghost and Resend accept the configured credentials and prints
+/// which features are enabled. Fails if any check did.
+pub async fn check_config(config: &Config) -> anyhow::Result<()> {
+    let mut failed = false;
+    let mut check = |name: &str, result: anyhow::Result<String>| match result {
+        Ok(detail) => println!("ok      {}: {}", name, detail),
+        Err(e) => {
+            println!("FAILED  {}: {}", name, e);
+            failed = true;
+        }
+    };
+
+    check(
+        "Ghost",
+        ghost::fetch_settings(config)
+            .await
+            .map(|settings| format!("{} ({})", settings.title, config.ghost_url)),
+    );
+    check("Resend", check_sending_domain(config).await);
+    check(
+        "Store",
+        Store::open(&config.data_dir).map(|store| {
+            let owner = match store.owner() {
+                Some(pid) => format!(", in use by process {}", pid),
+                None => String::new(),
+            };
+            format!(
+                "{} campaigns in {}{}",
+                store.campaigns().len(),
+                config.data_dir.display(),
+                owner
+            )
+        }),
+    );
+
+    let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+    println!();
+    println!(
+        "Public URL:       {}",
+        config.public_url.as_deref().unwrap_or("not set")
+    );
+    println!("Open tracking:    {}", on_off(config.open_tracking));
+    println!("Click tracking:   {}", on_off(config.click_tracking));
+    println!("UTM parameters:   {}", on_off(config.utm.is_some()));
+    println!(
+        "Admin API:        {}",
+        on_off(config.admin_token.is_some() || config.admin_password.is_some())
+    );
+    println!(
+        "Resend webhook:   {}",
+        on_off(config.resend_webhook_secret.is_some())
+    );
+    println!("Dry run:          {}", on_off(config.dry_run));
+    println!(
+        "Digest:           {}",
+        match &config.digest {
+            Some(digest) => match &digest.audience {
+                DigestAudience::Label(label) => {
+                    format!("\"{}\" to label {}", digest.schedule.source(), label)
+                }
+                DigestAudience::Newsletter(slug) => {
+                    format!("\"{}\" to newsletter {}", digest.schedule.source(), slug)
+                }
+            },
+            None => "off".to_string(),
+        }
+    );
+    println!(
+        "Welcome emails:   {}",
+        match config.welcome_emails.len() {
+            0 => "off".to_string(),
+            steps => format!("{} steps", steps),
+        }
+    );
+    println!(
+        "Engagement sync:  {}",
+        match config.ghost_engagement_interval {
+            Some(interval) => format!("every {} minutes", interval.as_secs() / 60),
+            None => "off".to_string(),
+        }
+    );
+
+    if failed {
+        return Err(anyhow!("Configuration check failed"));
+    }
+    Ok(())
+}
+
+/// Confirms the `FROM_EMAIL` domain is verified in Resend.
+async fn check_sending_domain(config: &Config) -> anyhow::Result<String> {
+    let domain = config
+        .from_email
+        .rsplit('@')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches('>')
+        .to_lowercase();
+
+    // Sending-only API keys can't list domains, sending still works with them
+    let domains = match Resend::new(&config.resend_api_key).domains.list().await {
+        Ok(domains) => domains,
+        Err(resend_rs::Error::Resend(e)) if e.name == "restricted_api_key" => {
+            return Ok(format!(
+                "sending-only API key, can't check that {} is verified",
+                domain
+            ))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    match domains
+        .iter()
+        .find(|d| d.name.eq_ignore_ascii_case(&domain))
+    {
+        Some(d) if d.status == "verified" => Ok(format!("{} is verified", domain)),
+        Some(d) => Err(anyhow!("{} is {}, not verified", domain, d.status)),
+        None => Err(anyhow!("{} is not a domain of this Resend account", domain)),
+    }
+}
+
+fn print_summary(summary: &CampaignSummary) {
+    println!("Campaign:   {}", summary.id);
+    println!("Post:       {} ({})", summary.post_title, summary.post_id);
+    println!(
+        "Created:    {}",
+        summary.created_at.format("%Y-%m-%d %H:%M UTC")
+    );
+    println!(
+        "Status:     {}{}{}",
+        status_name(summary.status),
+        if summary.dry_run { " (dry run)" } else { "" },
+        if summary.welcome { " (welcome)" } else { "" }
+    );
+    if let Some(send_at) = summary.send_at {
+        println!("Send at:    {}", send_at.format("%Y-%m-%d %H:%M UTC"));
+    }
+    println!(
+        "Recipients: {} sent, {} failed, {} skipped, {} pending",
+        summary.sent, summary.failed, summary.skipped, summary.pending
+    );
+}
+
+/// Prints every email of a dry run with its batch, then the totals.
+fn print_dry_run(report: &DryRunReport) {
+    for email in &report.sent {
+        println!(
+            "{}\t{}\t{}",
+            email.batch,
+            email.to.join(", "),
+            email.subject
+        );
+    }
+    for skipped in &report.skipped {
+        println!("-\t{}\tskipped: {}", skipped.email, skipped.reason);
+    }
+    println!(
+        "Dry run: {} emails in {} batches, {} skipped",
+        report.emails,
+        report.batches,
+        report.skipped.len()
+    );
+}
+
+fn status_name(status: CampaignStatus) -> String {
+    format!("{:?}", status).to_lowercase()
+}
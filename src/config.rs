@@ -1,4 +1,51 @@
+use crate::suppression::GhostAction;
+use crate::welcome::WelcomeStep;
 use anyhow::Result;
+use chrono::NaiveTime;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Whose name campaign emails are sent under.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SenderMode {
+    /// The configured `FROM_NAME`, or the site title
+    Site,
+    /// The post's primary author, at the `FROM_EMAIL` domain
+    Author,
+}
+
+/// UTM parameters appended to links in campaign emails.
+#[derive(Clone, Debug)]
+pub struct UtmParams {
+    pub source: String,
+    pub medium: String,
+    /// Campaign name template, `{post_slug}` is replaced with the post's slug
+    pub campaign: String,
+    /// Tag links to other sites too, not just the Ghost site's own
+    pub all_links: bool,
+}
+
+/// Digest subject when `DIGEST_SUBJECT` isn't set.
+pub const DEFAULT_DIGEST_SUBJECT: &str = "{site_title}: {post_count} new posts";
+
+/// Which members get the digest instead of an email per post.
+#[derive(Clone, Debug)]
+pub enum DigestAudience {
+    /// Members with this label, by slug
+    Label(String),
+    /// Members subscribed to this newsletter, by slug
+    Newsletter(String),
+}
+
+/// Periodic roundup of recent posts.
+#[derive(Clone, Debug)]
+pub struct DigestConfig {
+    /// When digests go out, in the site's timezone
+    pub schedule: cron::Schedule,
+    pub audience: DigestAudience,
+    /// Subject line template, `{site_title}` and `{post_count}` are filled in
+    pub subject_template: String,
+}
 
 #[derive(Clone)]
 pub struct Config {
@@ -9,12 +56,132 @@ pub struct Config {
     pub resend_api_key: String,
     pub from_email: String,
     pub port: u16,
+    /// Public base URL of this service, used for links back to it (e.g. unsubscribe)
+    pub public_url: Option<String>,
+    /// Secret used to sign links pointing back at this service
+    pub signing_secret: String,
+    /// Ghost's `members_email_auth_secret`, needed to sign Ghost's own unsubscribe links
+    pub ghost_members_secret: Option<String>,
+    /// Address offered as the mailto target of the `List-Unsubscribe` header
+    pub list_unsubscribe_mailto: Option<String>,
+    /// Subject line template, e.g. `[{site_title}] {post_title}`
+    pub subject_template: String,
+    /// Inbox preview text template, e.g. `{post_excerpt}`
+    pub preheader_template: String,
+    /// Display name for the sender, defaults to the Ghost site title
+    pub from_name: Option<String>,
+    /// Reply-to address, defaults to Ghost's members support address
+    pub reply_to: Option<String>,
+    pub sender_mode: SenderMode,
+    /// Signing secret of the Resend webhook (`whsec_...`)
+    pub resend_webhook_secret: Option<String>,
+    /// Directory where campaign and delivery state is stored
+    pub data_dir: PathBuf,
+    /// Changes made in Ghost when a member bounces or complains
+    pub ghost_suppression_actions: Vec<GhostAction>,
+    /// Label applied by the `label` suppression action
+    pub suppression_label: String,
+    /// Bearer token for the admin API
+    pub admin_token: Option<String>,
+    /// Basic auth credentials for the admin API, for use from a browser
+    pub admin_username: String,
+    pub admin_password: Option<String>,
+    /// Whether campaign emails carry an open tracking pixel, requires `PUBLIC_URL`
+    pub open_tracking: bool,
+    /// Whether links in campaign emails go through the click tracking redirect
+    pub click_tracking: bool,
+    /// Whether requests come through a reverse proxy whose `X-Forwarded-For` can
+    /// be trusted for the client address
+    pub trust_proxy: bool,
+    /// UTM parameters for outbound links, `None` unless enabled with `UTM_PARAMS`
+    pub utm: Option<UtmParams>,
+    /// How often email activity is pushed to Ghost members, `None` to never push it
+    pub ghost_engagement_interval: Option<Duration>,
+    /// Run every campaign without sending anything, recording what would be sent
+    pub dry_run: bool,
+    /// Time of day in the site's timezone campaigns are held until, e.g. `08:00`
+    pub send_time: Option<NaiveTime>,
+    /// How long campaigns are held after publishing, so they can still be cancelled
+    pub send_delay: Option<Duration>,
+    /// Deliver to each recipient at this time of day in their own timezone
+    pub local_delivery_time: Option<NaiveTime>,
+    /// Digest settings, `None` when `DIGEST_SCHEDULE` isn't set
+    pub digest: Option<DigestConfig>,
+    /// Emails sent to new members, in order, empty to send none
+    pub welcome_emails: Vec<WelcomeStep>,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self> {
         dotenv::dotenv().ok();
 
+        let webhook_secret = std::env::var("WEBHOOK_SECRET")
+            .map_err(|_| anyhow::anyhow!("WEBHOOK_SECRET environment variable not found"))?;
+
+        let public_url = std::env::var("PUBLIC_URL")
+            .ok()
+            .map(|url| url.trim_end_matches('/').to_string());
+
+        let open_tracking = matches!(
+            std::env::var("OPEN_TRACKING").as_deref(),
+            Ok("true") | Ok("1")
+        );
+        if open_tracking && public_url.is_none() {
+            return Err(anyhow::anyhow!(
+                "OPEN_TRACKING requires PUBLIC_URL to be set"
+            ));
+        }
+
+        let click_tracking = matches!(
+            std::env::var("CLICK_TRACKING").as_deref(),
+            Ok("true") | Ok("1")
+        );
+        if click_tracking && public_url.is_none() {
+            return Err(anyhow::anyhow!(
+                "CLICK_TRACKING requires PUBLIC_URL to be set"
+            ));
+        }
+
+        let digest = match std::env::var("DIGEST_SCHEDULE") {
+            Ok(schedule) => {
+                // Standard five field expressions, the cron crate wants seconds too
+                let expression = if schedule.split_whitespace().count() == 5 {
+                    format!("0 {}", schedule)
+                } else {
+                    schedule.clone()
+                };
+                let audience = match (
+                    std::env::var("DIGEST_LABEL"),
+                    std::env::var("DIGEST_NEWSLETTER"),
+                ) {
+                    (Ok(label), _) => DigestAudience::Label(label),
+                    (_, Ok(newsletter)) => DigestAudience::Newsletter(newsletter),
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "DIGEST_SCHEDULE requires DIGEST_LABEL or DIGEST_NEWSLETTER"
+                        ))
+                    }
+                };
+                // Both go into a quoted NQL filter
+                let (DigestAudience::Label(slug) | DigestAudience::Newsletter(slug)) = &audience;
+                if slug.is_empty() || slug.contains('\'') {
+                    return Err(anyhow::anyhow!(
+                        "Invalid digest label or newsletter slug '{}'",
+                        slug
+                    ));
+                }
+                Some(DigestConfig {
+                    schedule: expression.parse().map_err(|e| {
+                        anyhow::anyhow!("Invalid DIGEST_SCHEDULE '{}': {}", schedule, e)
+                    })?,
+                    audience,
+                    subject_template: std::env::var("DIGEST_SUBJECT")
+                        .unwrap_or_else(|_| DEFAULT_DIGEST_SUBJECT.to_string()),
+                })
+            }
+            Err(_) => None,
+        };
+
         Ok(Config {
             ghost_url: std::env::var("GHOST_URL")
                 .map_err(|_| anyhow::anyhow!("GHOST_URL environment variable not found"))?,
@@ -23,8 +190,6 @@ impl Config {
             ghost_admin_secret: std::env::var("GHOST_ADMIN_SECRET").map_err(|_| {
                 anyhow::anyhow!("GHOST_ADMIN_SECRET environment variable not found")
             })?,
-            webhook_secret: std::env::var("WEBHOOK_SECRET")
-                .map_err(|_| anyhow::anyhow!("WEBHOOK_SECRET environment variable not found"))?,
             resend_api_key: std::env::var("RESEND_API_KEY")
                 .map_err(|_| anyhow::anyhow!("RESEND_API_KEY environment variable not found"))?,
             from_email: std::env::var("FROM_EMAIL")
@@ -33,6 +198,112 @@ impl Config {
                 .unwrap_or_else(|_| "3000".to_string())
                 .parse()
                 .map_err(|_| anyhow::anyhow!("PORT must be a valid number"))?,
+            signing_secret: std::env::var("SIGNING_SECRET")
+                .unwrap_or_else(|_| webhook_secret.clone()),
+            ghost_members_secret: std::env::var("GHOST_MEMBERS_SECRET").ok(),
+            list_unsubscribe_mailto: std::env::var("LIST_UNSUBSCRIBE_MAILTO").ok(),
+            subject_template: std::env::var("SUBJECT_TEMPLATE")
+                .unwrap_or_else(|_| "{post_title}".to_string()),
+            preheader_template: std::env::var("PREHEADER_TEMPLATE")
+                .unwrap_or_else(|_| "{post_excerpt}".to_string()),
+            from_name: std::env::var("FROM_NAME").ok(),
+            reply_to: std::env::var("REPLY_TO").ok(),
+            sender_mode: match std::env::var("SENDER_MODE").as_deref() {
+                Err(_) | Ok("site") => SenderMode::Site,
+                Ok("author") => SenderMode::Author,
+                Ok(other) => {
+                    return Err(anyhow::anyhow!(
+                        "SENDER_MODE must be 'site' or 'author', got '{}'",
+                        other
+                    ))
+                }
+            },
+            resend_webhook_secret: std::env::var("RESEND_WEBHOOK_SECRET").ok(),
+            data_dir: std::env::var("DATA_DIR")
+                .unwrap_or_else(|_| "data".to_string())
+                .into(),
+            ghost_suppression_actions: std::env::var("GHOST_SUPPRESSION_ACTIONS")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|action| !action.trim().is_empty())
+                .map(|action| action.parse())
+                .collect::<Result<_>>()?,
+            suppression_label: std::env::var("SUPPRESSION_LABEL")
+                .unwrap_or_else(|_| "bounced".to_string()),
+            admin_token: std::env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            admin_username: std::env::var("ADMIN_USERNAME").unwrap_or_else(|_| "admin".to_string()),
+            admin_password: std::env::var("ADMIN_PASSWORD")
+                .ok()
+                .filter(|password| !password.is_empty()),
+            public_url,
+            open_tracking,
+            click_tracking,
+            trust_proxy: matches!(
+                std::env::var("TRUST_PROXY").as_deref(),
+                Ok("true") | Ok("1")
+            ),
+            utm: match std::env::var("UTM_PARAMS").as_deref() {
+                Ok("true") | Ok("1") => Some(UtmParams {
+                    source: std::env::var("UTM_SOURCE")
+                        .unwrap_or_else(|_| "newsletter".to_string()),
+                    medium: std::env::var("UTM_MEDIUM").unwrap_or_else(|_| "email".to_string()),
+                    campaign: std::env::var("UTM_CAMPAIGN")
+                        .unwrap_or_else(|_| "{post_slug}".to_string()),
+                    all_links: matches!(
+                        std::env::var("UTM_ALL_LINKS").as_deref(),
+                        Ok("true") | Ok("1")
+                    ),
+                }),
+                _ => None,
+            },
+            ghost_engagement_interval: match std::env::var("GHOST_ENGAGEMENT_SYNC_MINUTES") {
+                Ok(minutes) => match minutes.parse::<u64>() {
+                    Ok(0) => None,
+                    Ok(minutes) => Some(Duration::from_secs(minutes * 60)),
+                    Err(_) => {
+                        return Err(anyhow::anyhow!(
+                            "GHOST_ENGAGEMENT_SYNC_MINUTES must be a valid number"
+                        ))
+                    }
+                },
+                Err(_) => None,
+            },
+            send_time: match std::env::var("SEND_TIME") {
+                Ok(time) => Some(NaiveTime::parse_from_str(&time, "%H:%M").map_err(|_| {
+                    anyhow::anyhow!("SEND_TIME must be a time like 08:00, got '{}'", time)
+                })?),
+                Err(_) => None,
+            },
+            digest,
+            welcome_emails: std::env::var("WELCOME_EMAILS")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|step| !step.trim().is_empty())
+                .map(|step| step.parse())
+                .collect::<Result<_>>()?,
+            local_delivery_time: match std::env::var("LOCAL_DELIVERY_TIME") {
+                Ok(time) => Some(NaiveTime::parse_from_str(&time, "%H:%M").map_err(|_| {
+                    anyhow::anyhow!(
+                        "LOCAL_DELIVERY_TIME must be a time like 08:00, got '{}'",
+                        time
+                    )
+                })?),
+                Err(_) => None,
+            },
+            send_delay: match std::env::var("SEND_DELAY_MINUTES") {
+                Ok(minutes) => match minutes.parse::<u64>() {
+                    Ok(0) => None,
+                    Ok(minutes) => Some(Duration::from_secs(minutes * 60)),
+                    Err(_) => {
+                        return Err(anyhow::anyhow!("SEND_DELAY_MINUTES must be a valid number"))
+                    }
+                },
+                Err(_) => None,
+            },
+            dry_run: matches!(std::env::var("DRY_RUN").as_deref(), Ok("true") | Ok("1")),
+            webhook_secret,
         })
     }
 }
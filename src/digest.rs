@@ -0,0 +1,276 @@
+use crate::{
+    config::{Config, DigestAudience},
+    email, ghost,
+    ghost::{Member, Post},
+    preview::sample_member,
+    queue::{Queue, SendOptions},
+    schedule,
+    store::{Campaign, Store},
+};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use std::time::Duration;
+
+// How far back the first digest looks, before there is a last one to start from
+const FIRST_DIGEST_WINDOW: TimeDelta = TimeDelta::days(7);
+
+// Wait before trying again when Ghost can't be reached to work out the next run
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Ghost NQL filter for the members who get the digest.
+pub fn member_filter(audience: &DigestAudience) -> String {
+    match audience {
+        DigestAudience::Label(label) => format!("subscribed:true+label:'{}'", label),
+        DigestAudience::Newsletter(slug) => {
+            format!("subscribed:true+newsletters.slug:'{}'", slug)
+        }
+    }
+}
+
+/// Whether a member gets the digest, and with it no email per post. Labels and
+/// newsletters match by slug, like `member_filter` does.
+pub fn is_opted_in(audience: &DigestAudience, member: &Member) -> bool {
+    let (key, wanted) = match audience {
+        DigestAudience::Label(slug) => ("labels", slug),
+        DigestAudience::Newsletter(slug) => ("newsletters", slug),
+    };
+
+    member
+        .other
+        .get(key)
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.get("slug")?.as_str())
+        .any(|slug| slug.eq_ignore_ascii_case(wanted))
+}
+
+/// The posts the next digest would cover: published since the last digest, or
+/// the past week before the first, up to `until`.
+pub async fn pending_posts(
+    config: &Config,
+    store: &Store,
+    until: DateTime<Utc>,
+) -> anyhow::Result<Vec<Post>> {
+    let since = store
+        .last_digest_at()
+        .unwrap_or(until - FIRST_DIGEST_WINDOW);
+    ghost::fetch_published_posts(config, since, until).await
+}
+
+/// Queues a digest of the posts published since the last one. Returns `None`
+/// when there was nothing new to send. Dry runs leave the window where it is,
+/// so the real digest still covers the same posts.
+pub async fn send_digest(
+    config: &Config,
+    store: &Store,
+    queue: &Queue,
+    options: SendOptions,
+) -> anyhow::Result<Option<Campaign>> {
+    let until = Utc::now();
+    let posts = pending_posts(config, store, until).await?;
+    if posts.is_empty() {
+        tracing::info!("No posts published since the last digest, skipping it");
+        return Ok(None);
+    }
+
+    let campaign = queue.enqueue_digest(posts, options).await?;
+    if !options.dry_run {
+        store.set_last_digest_at(until);
+    }
+    Ok(Some(campaign))
+}
+
+/// Starts sending digests on the `DIGEST_SCHEDULE`, read in the site's timezone.
+pub fn spawn_task(config: &Config, store: &Store, queue: &Queue) {
+    let Some(digest) = config.digest.clone() else {
+        return;
+    };
+
+    let config = config.clone();
+    let store = store.clone();
+    let queue = queue.clone();
+    tokio::spawn(async move {
+        loop {
+            // Ghost's timezone can change, so look it up for every run
+            let timezone = match ghost::fetch_settings(&config).await {
+                Ok(settings) => schedule::site_timezone(&settings.timezone),
+                Err(e) => {
+                    tracing::error!("Failed to fetch Ghost settings for the digest: {}", e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            let Some(next) = digest
+                .schedule
+                .after(&Utc::now().with_timezone(&timezone))
+                .next()
+            else {
+                tracing::warn!("DIGEST_SCHEDULE has no upcoming times, stopping digests");
+                return;
+            };
+            tracing::info!("Next digest at {}", next);
+
+            let wait = (next.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .unwrap_or_default();
+            tokio::time::sleep(wait).await;
+
+            let options = SendOptions {
+                dry_run: config.dry_run,
+                ..SendOptions::default()
+            };
+            if let Err(e) = send_digest(&config, &store, &queue, options).await {
+                tracing::error!("Failed to send digest: {}", e);
+            }
+        }
+    });
+}
+
+/// Renders the next digest as the sample member would receive it.
+pub async fn render_next_digest(config: &Config, store: &Store) -> anyhow::Result<String> {
+    let digest = config
+        .digest
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Digests are not configured"))?;
+    let posts = pending_posts(config, store, Utc::now()).await?;
+    let settings = ghost::fetch_settings(config).await?;
+
+    let compiled = email::compile_digest(&posts, &digest.subject_template, config, &settings)?;
+    Ok(compiled.personalize(&sample_member(), config).html)
+}
+
+/// Shows the next digest in the browser.
+pub async fn preview_digest(
+    State(config): State<Config>,
+    State(store): State<Store>,
+) -> Result<Html<String>, StatusCode> {
+    if config.digest.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    render_next_digest(&config, &store)
+        .await
+        .map(Html)
+        .map_err(|e| {
+            tracing::error!("Failed to render digest preview: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DigestParams {
+    /// Go through the whole digest without sending anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Sends the digest now instead of waiting for the schedule. Answers 204 when
+/// no posts were published since the last one.
+pub async fn handle_send_digest(
+    State(config): State<Config>,
+    State(store): State<Store>,
+    State(queue): State<Queue>,
+    Query(params): Query<DigestParams>,
+) -> Result<Response, StatusCode> {
+    if config.digest.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let options = SendOptions {
+        dry_run: config.dry_run || params.dry_run,
+        ..SendOptions::default()
+    };
+    let campaign = send_digest(&config, &store, &queue, options)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to send digest: {}", e);
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    match campaign {
+        Some(campaign) => {
+            tracing::info!("Sending digest as campaign {}", campaign.id);
+            let summary = store.campaign_summary(campaign);
+            Ok((StatusCode::ACCEPTED, Json(summary)).into_response())
+        }
+        None => Ok(StatusCode::NO_CONTENT.into_response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn member(other: serde_json::Value) -> Member {
+        Member {
+            id: "id".to_string(),
+            uuid: "uuid".to_string(),
+            email: "jamie@example.com".to_string(),
+            name: None,
+            status: "free".to_string(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            other,
+        }
+    }
+
+    #[test]
+    fn filters_members_by_label_or_newsletter() {
+        assert_eq!(
+            member_filter(&DigestAudience::Label("weekly-digest".to_string())),
+            "subscribed:true+label:'weekly-digest'"
+        );
+        assert_eq!(
+            member_filter(&DigestAudience::Newsletter("roundup".to_string())),
+            "subscribed:true+newsletters.slug:'roundup'"
+        );
+    }
+
+    #[test]
+    fn opts_in_members_by_label_slug() {
+        let audience = DigestAudience::Label("weekly-digest".to_string());
+        let labelled = member(json!({
+            "labels": [{ "name": "Weekly digest", "slug": "Weekly-Digest" }]
+        }));
+        assert!(is_opted_in(&audience, &labelled));
+
+        // The label name alone doesn't count
+        let named = member(json!({
+            "labels": [{ "name": "weekly-digest", "slug": "other" }]
+        }));
+        assert!(!is_opted_in(&audience, &named));
+
+        // Newsletters don't count for a label audience
+        let subscribed = member(json!({ "newsletters": [{ "slug": "weekly-digest" }] }));
+        assert!(!is_opted_in(&audience, &subscribed));
+        assert!(!is_opted_in(&audience, &member(serde_json::Value::Null)));
+    }
+
+    #[test]
+    fn opts_in_members_by_newsletter_slug() {
+        let audience = DigestAudience::Newsletter("roundup".to_string());
+        let subscribed = member(json!({
+            "newsletters": [{ "slug": "default-newsletter" }, { "slug": "roundup" }]
+        }));
+        assert!(is_opted_in(&audience, &subscribed));
+
+        let other = member(json!({
+            "newsletters": [{ "slug": "default-newsletter" }],
+            "labels": [{ "slug": "roundup" }]
+        }));
+        assert!(!is_opted_in(&audience, &other));
+        assert!(!is_opted_in(
+            &audience,
+            &member(json!({ "newsletters": [] }))
+        ));
+    }
+}
@@ -1,6 +1,22 @@
+mod cards;
+mod digest;
+mod personalize;
+mod template;
+mod text;
+mod urls;
+
+use crate::config::SenderMode;
 use crate::ghost;
 use crate::ghost::{Member, Post};
+use crate::{tracking, unsubscribe};
+use cards::CardOptions;
+use regex::{Captures, Regex};
 use serde::Serialize;
+use std::sync::OnceLock;
+use template::{RecipientValues, Slot, Template};
+
+pub use digest::{compile_digest, digest_list_id};
+pub use text::html_to_text;
 
 #[derive(Serialize)]
 struct EmailTemplate {
@@ -40,44 +56,197 @@ struct NewsletterInfo {
     unsubscribe_link: String,
 }
 
-pub async fn format_email(
+/// `List-Id` header identifying the newsletter a post was sent to, e.g.
+/// `"Weekly" <weekly.example.com>`. Falls back to the site itself when the post
+/// doesn't carry a newsletter.
+pub fn list_id(post: &Post, settings: &ghost::Settings) -> String {
+    let host = reqwest::Url::parse(&settings.url)
+        .ok()
+        .and_then(|url| url.host_str().map(|h| h.to_string()))
+        .unwrap_or_else(|| "localhost".to_string());
+
+    let newsletter = post.other.get("newsletter");
+    let slug = newsletter
+        .and_then(|n| n.get("slug"))
+        .and_then(|s| s.as_str())
+        .unwrap_or("default-newsletter");
+    let name = newsletter
+        .and_then(|n| n.get("name"))
+        .and_then(|s| s.as_str())
+        .unwrap_or(&settings.title);
+
+    format!("\"{}\" <{}.{}>", name.replace('"', ""), slug, host)
+}
+
+/// `From` header for a campaign, e.g. `"Site Title" <blog@example.com>`.
+///
+/// In author mode the post's primary author is the sender, using their slug as the
+/// mailbox at the `FROM_EMAIL` domain.
+pub fn from_address(
+    post: &Post,
+    config: &crate::config::Config,
+    settings: &ghost::Settings,
+) -> String {
+    let (name, address) = match config.sender_mode {
+        SenderMode::Site => return site_from_address(config, settings),
+        SenderMode::Author => {
+            let author = &post.primary_author;
+            let address = match (&author.slug, config.from_email.split_once('@')) {
+                (Some(slug), Some((_, domain))) => format!("{}@{}", slug, domain),
+                _ => config.from_email.clone(),
+            };
+            (author.name.as_str(), address)
+        }
+    };
+
+    format!("\"{}\" <{}>", name.replace('"', ""), address)
+}
+
+/// `From` header naming the site, for emails that aren't from a single author.
+pub fn site_from_address(config: &crate::config::Config, settings: &ghost::Settings) -> String {
+    let name = config.from_name.as_deref().unwrap_or(&settings.title);
+    format!("\"{}\" <{}>", name.replace('"', ""), config.from_email)
+}
+
+/// Reply-to address for campaigns: the configured `REPLY_TO`, or Ghost's members
+/// support address. Ghost stores the latter without a domain when it is on the
+/// site's own domain, and defaults it to `noreply`.
+pub fn reply_to(config: &crate::config::Config, settings: &ghost::Settings) -> Option<String> {
+    if let Some(reply_to) = &config.reply_to {
+        return Some(reply_to.clone());
+    }
+
+    let support = settings.members_support_address.as_deref()?.trim();
+    if support.contains('@') {
+        return Some(support.to_string());
+    }
+    if support.is_empty() || support == "noreply" {
+        return None;
+    }
+
+    let host = reqwest::Url::parse(&settings.url)
+        .ok()?
+        .host_str()?
+        .trim_start_matches("www.")
+        .to_string();
+    Some(format!("{}@{}", support, host))
+}
+
+/// Fills `{site_title}`, `{post_title}`, `{post_excerpt}` and `{author_name}` in a
+/// subject or preheader template.
+///
+/// Ghost's `email_subject` replaces the post title and `custom_excerpt` the
+/// generated excerpt when the post sets them.
+fn fill_template(template: &str, post: &Post, settings: &ghost::Settings) -> String {
+    let other_str = |key: &str| {
+        post.other
+            .get(key)
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.trim().is_empty())
+    };
+
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    let placeholder = PLACEHOLDER.get_or_init(|| {
+        Regex::new(r"\{(site_title|post_title|post_excerpt|author_name)\}")
+            .expect("template placeholder regex is valid")
+    });
+
+    // One pass, so a title or excerpt with braces in it isn't filled in again
+    placeholder
+        .replace_all(template, |caps: &Captures| match &caps[1] {
+            "site_title" => settings.title.as_str(),
+            "post_title" => other_str("email_subject").unwrap_or(&post.title),
+            "post_excerpt" => other_str("custom_excerpt").unwrap_or(&post.excerpt),
+            _ => post.primary_author.name.as_str(),
+        })
+        .into_owned()
+}
+
+/// An email rendered once per campaign, ready to be personalized for each
+/// recipient by filling in the merge tags and slots left in it.
+#[derive(Debug)]
+pub struct CompiledEmail {
+    subject: Template,
+    html: Template,
+    /// Targets of the links replaced by `Slot::TrackedLink` markers
+    links: Vec<String>,
+    campaign_id: Option<String>,
+}
+
+/// A compiled email filled in for one recipient.
+pub struct PersonalizedEmail {
+    pub subject: String,
+    pub html: String,
+}
+
+impl CompiledEmail {
+    /// Ties the email to a campaign, which enables tracking when it is configured.
+    pub fn for_campaign(mut self, campaign_id: &str) -> Self {
+        self.campaign_id = Some(campaign_id.to_string());
+        self
+    }
+
+    /// Fills in the subject and HTML for a member.
+    pub fn personalize(
+        &self,
+        member: &Member,
+        config: &crate::config::Config,
+    ) -> PersonalizedEmail {
+        let values = RecipientValues {
+            member,
+            unsubscribe_link: unsubscribe::unsubscribe_link(config, &member.uuid),
+            open_pixel: self
+                .campaign_id
+                .as_deref()
+                .filter(|_| config.open_tracking)
+                .map(|campaign_id| tracking::open_pixel(config, campaign_id, &member.uuid)),
+            links: match &self.campaign_id {
+                Some(campaign_id) => self
+                    .links
+                    .iter()
+                    .map(|link| tracking::click_link(config, campaign_id, &member.uuid, link))
+                    .collect(),
+                // Untracked renders like previews link straight to the target
+                None => self.links.clone(),
+            },
+        };
+
+        PersonalizedEmail {
+            subject: self.subject.render(&values),
+            html: self.html.render(&values),
+        }
+    }
+}
+
+/// Renders a single email for one member. Campaigns should use `compile_email`
+/// once and personalize the result instead.
+pub fn format_email(
     post: &Post,
     member: &Member,
     config: &crate::config::Config,
     settings: &ghost::Settings,
 ) -> anyhow::Result<String> {
+    Ok(compile_email(post, config, settings)?
+        .personalize(member, config)
+        .html)
+}
+
+/// Renders the post into a `CompiledEmail`. Everything that is the same for all
+/// recipients happens here, so sending to a large list only pays for it once.
+pub fn compile_email(
+    post: &Post,
+    config: &crate::config::Config,
+    settings: &ghost::Settings,
+) -> anyhow::Result<CompiledEmail> {
+    let accent_color = accent_color(settings);
+
+    // Inbox preview text, followed by filler so the body doesn't leak into the preview
+    let preheader_html = preheader_html(&fill_template(&config.preheader_template, post, settings));
+
     let template = EmailTemplate {
-        site: SiteInfo {
-            url: settings.url.clone(),
-            title: settings.title.clone(),
-            description: settings.description.clone(),
-            color: settings
-                .accent_color
-                .clone()
-                .unwrap_or_else(|| "#3eb0ef".to_string()),
-        },
-        post: PostContent {
-            id: post.id.clone(),
-            url: post.url.clone(),
-            title: post.title.clone(),
-            html: post.html.clone(),
-            excerpt: post.excerpt.clone(),
-            author: post.primary_author.name.clone(),
-            author_image: post.primary_author.profile_image.clone(),
-            author_bio: post.primary_author.bio.clone(),
-            author_url: post.primary_author.url.clone(),
-            feature_image: post.feature_image.clone(),
-            feature_image_alt: post.feature_image_alt.clone(),
-            feature_image_caption: post.feature_image_caption.clone(),
-            reading_time: post.reading_time,
-        },
-        newsletter: NewsletterInfo {
-            subscription_link: format!("{}#/portal/account", config.ghost_url),
-            unsubscribe_link: format!(
-                "{}#/portal/account?action=unsubscribe&uuid={}",
-                config.ghost_url, member.id
-            ),
-        },
+        site: SiteInfo::new(settings, accent_color.clone()),
+        post: PostContent::new(post, settings, &accent_color)?,
+        newsletter: NewsletterInfo::new(config),
     };
 
     let html = format!(
@@ -245,6 +414,7 @@ pub async fn format_email(
             </style>
         </head>
         <body>
+            {preheader_html}
             <div class="container">
                 <div class="header">
                     <h1>{site_title}</h1>
@@ -282,9 +452,11 @@ pub async fn format_email(
                     </p>
                 </div>
             </div>
+            {open_pixel}
         </body>
         </html>
     "#,
+        preheader_html = preheader_html,
         site_title = template.site.title,
         post_url = template.post.url,
         post_title = template.post.title,
@@ -327,8 +499,409 @@ pub async fn format_email(
             }),
         post_content = template.post.html,
         subscription_link = template.newsletter.subscription_link,
-        unsubscribe_link = template.newsletter.unsubscribe_link
+        unsubscribe_link = template.newsletter.unsubscribe_link,
+        open_pixel = if config.open_tracking {
+            Slot::OpenPixel.marker()
+        } else {
+            String::new()
+        }
     );
 
-    Ok(html)
+    finish(
+        html,
+        &fill_template(&config.subject_template, post, settings),
+        &post.slug,
+        config,
+        settings,
+    )
+}
+
+/// Shared last steps of compiling an email: UTM parameters, click tracking
+/// slots and the subject and HTML templates.
+fn finish(
+    html: String,
+    subject: &str,
+    slug: &str,
+    config: &crate::config::Config,
+    settings: &ghost::Settings,
+) -> anyhow::Result<CompiledEmail> {
+    // Attribute newsletter traffic in the site's analytics
+    let html = match &config.utm {
+        Some(utm) => {
+            let campaign = utm.campaign.replace("{post_slug}", slug);
+            urls::add_utm_params(
+                &html,
+                &settings.url,
+                &[
+                    ("utm_source", utm.source.as_str()),
+                    ("utm_medium", utm.medium.as_str()),
+                    ("utm_campaign", campaign.as_str()),
+                ],
+                utm.all_links,
+            )?
+        }
+        None => html,
+    };
+
+    // Point links at the click tracking redirect, filled in per recipient
+    let (html, links) = if config.click_tracking {
+        urls::extract_links(&html)?
+    } else {
+        (html, Vec::new())
+    };
+
+    Ok(CompiledEmail {
+        subject: Template::compile(subject, false),
+        html: Template::compile(&html, true),
+        links,
+        campaign_id: None,
+    })
+}
+
+fn accent_color(settings: &ghost::Settings) -> String {
+    settings
+        .accent_color
+        .clone()
+        .unwrap_or_else(|| "#3eb0ef".to_string())
+}
+
+/// Hidden inbox preview text, padded so the body doesn't show up after it.
+fn preheader_html(preheader: &str) -> String {
+    if preheader.trim().is_empty() {
+        return String::new();
+    }
+    format!(
+        r#"<div style="display:none;font-size:1px;line-height:1px;max-height:0;max-width:0;opacity:0;overflow:hidden;mso-hide:all;">{}{}</div>"#,
+        escape_html(preheader),
+        "&#8199;&#65279;&#847; ".repeat(60)
+    )
+}
+
+impl SiteInfo {
+    fn new(settings: &ghost::Settings, color: String) -> Self {
+        SiteInfo {
+            url: settings.url.clone(),
+            title: settings.title.clone(),
+            description: settings.description.clone(),
+            color,
+        }
+    }
+}
+
+impl PostContent {
+    /// The post ready for an email: cards rewritten for email clients and every
+    /// link and image made absolute.
+    fn new(post: &Post, settings: &ghost::Settings, accent_color: &str) -> anyhow::Result<Self> {
+        // Rewrite Koenig cards into markup that survives email clients
+        let html = cards::render_cards(
+            &post.html,
+            &CardOptions {
+                post_url: &post.url,
+                accent_color,
+            },
+        )?;
+
+        // Relative links and images break once the HTML leaves the site
+        let html = urls::absolutize_urls(&html, &settings.url)?;
+        let absolutize = |url: Option<&str>| {
+            url.map(|url| urls::absolutize(&settings.url, url))
+                .transpose()
+        };
+
+        Ok(PostContent {
+            id: post.id.clone(),
+            url: post.url.clone(),
+            title: escape_html(&post.title),
+            html,
+            excerpt: post.excerpt.clone(),
+            author: post.primary_author.name.clone(),
+            author_image: absolutize(post.primary_author.profile_image.as_deref())?,
+            author_bio: post.primary_author.bio.clone(),
+            author_url: post.primary_author.url.clone(),
+            feature_image: absolutize(post.feature_image.as_deref())?,
+            feature_image_alt: post.feature_image_alt.clone(),
+            feature_image_caption: post.feature_image_caption.clone(),
+            reading_time: post.reading_time,
+        })
+    }
+}
+
+impl NewsletterInfo {
+    fn new(config: &crate::config::Config) -> Self {
+        NewsletterInfo {
+            subscription_link: format!("{}#/portal/account", config.ghost_url),
+            unsubscribe_link: Slot::UnsubscribeLink.marker(),
+        }
+    }
+}
+
+pub(crate) fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use personalize::{member_field, merge_tag_regex};
+    use serde_json::json;
+
+    fn config() -> Config {
+        static ENV: OnceLock<()> = OnceLock::new();
+        ENV.get_or_init(|| {
+            for (key, value) in [
+                ("GHOST_URL", "https://blog.example.com"),
+                ("GHOST_ADMIN_ID", "test"),
+                ("GHOST_ADMIN_SECRET", "00"),
+                ("WEBHOOK_SECRET", "test-secret"),
+                ("RESEND_API_KEY", "re_test"),
+                ("FROM_EMAIL", "blog@example.com"),
+                ("PUBLIC_URL", "https://mailer.example.com"),
+            ] {
+                std::env::set_var(key, value);
+            }
+        });
+        let mut config = Config::from_env().expect("test config is complete");
+        config.subject_template = r#"{post_title} for {first_name, "you"}"#.to_string();
+        config.preheader_template = "{post_excerpt}".to_string();
+        config
+    }
+
+    fn settings() -> ghost::Settings {
+        ghost::Settings {
+            title: "Example Blog".to_string(),
+            description: "A blog".to_string(),
+            accent_color: None,
+            url: "https://blog.example.com".to_string(),
+            members_support_address: None,
+            timezone: "Etc/UTC".to_string(),
+        }
+    }
+
+    fn post(title: &str, other: serde_json::Value) -> Post {
+        let mut post = json!({
+            "id": "post-id",
+            "uuid": "post-uuid",
+            "title": title,
+            "slug": "hello",
+            "html": r#"<p>Hi {first_name, &quot;there&quot;}, you're {email}.</p>
+<p><a href="/related/">Related</a> and <a href="https://other.example/">elsewhere</a>, <a href="/related/">again</a>.</p>
+<figure class="kg-card kg-image-card"><img src="/content/images/photo.jpg" alt=""></figure>"#,
+            "feature_image": null,
+            "featured": false,
+            "status": "published",
+            "visibility": "public",
+            "created_at": "2024-01-01T00:00:00.000Z",
+            "updated_at": "2024-01-01T00:00:00.000Z",
+            "url": "https://blog.example.com/hello/",
+            "excerpt": "The generated excerpt",
+            "primary_author": {
+                "name": "Author",
+                "profile_image": null,
+                "bio": null,
+                "url": "https://blog.example.com/author/author/"
+            },
+            "reading_time": 2,
+            "feature_image_alt": null,
+            "feature_image_caption": null
+        });
+        if let (Some(post), Some(other)) = (post.as_object_mut(), other.as_object()) {
+            post.extend(other.clone());
+        }
+        serde_json::from_value(post).expect("test post is valid")
+    }
+
+    fn member(name: Option<&str>) -> Member {
+        Member {
+            id: "member-id".to_string(),
+            uuid: "00000000-0000-0000-0000-000000000001".to_string(),
+            email: "jamie@example.com".to_string(),
+            name: name.map(|name| name.to_string()),
+            status: "free".to_string(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            other: serde_json::Value::Null,
+        }
+    }
+
+    /// Fills merge tags the way emails were rendered before compiling, with
+    /// member values substituted into the post before anything else.
+    fn personalize_source(source: &str, member: &Member, escape: bool) -> String {
+        merge_tag_regex()
+            .replace_all(source, |caps: &Captures| {
+                let Some(field) = caps.get(2) else {
+                    return caps[0].to_string();
+                };
+                let fallback = caps.get(3).map_or("", |m| m.as_str());
+                match member_field(member, field.as_str()) {
+                    Some(value) if escape => escape_html(&value),
+                    Some(value) => value,
+                    None if escape => fallback.to_string(),
+                    None => fallback.replace("&quot;", "\""),
+                }
+            })
+            .into_owned()
+    }
+
+    /// The email a member got from the per-member path: the post personalized
+    /// first, then rendered with only the slots left to fill.
+    fn render_for_member(
+        post: &Post,
+        member: &Member,
+        config: &Config,
+        settings: &ghost::Settings,
+    ) -> PersonalizedEmail {
+        let mut personal_post = post.clone();
+        personal_post.html = personalize_source(&post.html, member, true);
+        personal_post.title = personalize_source(&post.title, member, false);
+        let mut personal_config = config.clone();
+        personal_config.subject_template = personalize_source(
+            &fill_template(&config.subject_template, post, settings),
+            member,
+            false,
+        );
+        personal_config.preheader_template = personalize_source(
+            &fill_template(&config.preheader_template, post, settings),
+            member,
+            false,
+        );
+
+        compile_email(&personal_post, &personal_config, settings)
+            .unwrap()
+            .for_campaign("campaign-id")
+            .personalize(member, config)
+    }
+
+    fn assert_same_email(config: &Config, member: &Member) {
+        let settings = settings();
+        let post = post(r#"Hello {first_name, "friend"} & co"#, json!({}));
+
+        let expected = render_for_member(&post, member, config, &settings);
+        let compiled = compile_email(&post, config, &settings)
+            .unwrap()
+            .for_campaign("campaign-id")
+            .personalize(member, config);
+
+        assert_eq!(compiled.subject, expected.subject);
+        assert_eq!(compiled.html, expected.html);
+        assert_eq!(
+            html_to_text(&compiled.html).unwrap(),
+            html_to_text(&expected.html).unwrap()
+        );
+    }
+
+    #[test]
+    fn fills_placeholders_in_a_single_pass() {
+        let post = post("Braces {post_excerpt} and {site_title}", json!({}));
+        assert_eq!(
+            fill_template("{post_title} | {author_name}", &post, &settings()),
+            "Braces {post_excerpt} and {site_title} | Author"
+        );
+    }
+
+    #[test]
+    fn prefers_email_subject_and_custom_excerpt() {
+        let template = "{post_title}: {post_excerpt}";
+        let post_with = |other| post("Post title", other);
+
+        assert_eq!(
+            fill_template(template, &post_with(json!({})), &settings()),
+            "Post title: The generated excerpt"
+        );
+        assert_eq!(
+            fill_template(
+                template,
+                &post_with(json!({
+                    "email_subject": "Email subject",
+                    "custom_excerpt": "Custom excerpt"
+                })),
+                &settings()
+            ),
+            "Email subject: Custom excerpt"
+        );
+        // Blank overrides are ignored
+        assert_eq!(
+            fill_template(
+                template,
+                &post_with(json!({ "email_subject": " ", "custom_excerpt": null })),
+                &settings()
+            ),
+            "Post title: The generated excerpt"
+        );
+    }
+
+    #[test]
+    fn digest_lists_posts_without_their_body() {
+        let config = config();
+        let post = post(
+            "A <new> post",
+            json!({ "feature_image": "/content/images/feature.jpg" }),
+        );
+        let email = compile_digest(
+            &[post],
+            "{post_count} from {site_title}",
+            &config,
+            &settings(),
+        )
+        .unwrap()
+        .personalize(&member(None), &config);
+
+        assert_eq!(email.subject, "1 from Example Blog");
+        assert!(email.html.contains("A &lt;new&gt; post"));
+        assert!(email
+            .html
+            .contains(r#"<img src="https://blog.example.com/content/images/feature.jpg""#));
+        assert!(email.html.contains("The generated excerpt"));
+        assert!(!email.html.contains("jamie@example.com"));
+    }
+
+    #[test]
+    fn compiled_email_matches_per_member_render() {
+        let config = config();
+        assert_same_email(&config, &member(Some("Jamie <b>Larson</b>")));
+        assert_same_email(&config, &member(None));
+    }
+
+    #[test]
+    fn compiled_email_matches_per_member_render_with_click_tracking() {
+        let mut config = config();
+        config.click_tracking = true;
+        config.open_tracking = true;
+        assert_same_email(&config, &member(Some("Jamie Larson")));
+        assert_same_email(&config, &member(None));
+
+        let email = compile_email(&post("Hello", json!({})), &config, &settings())
+            .unwrap()
+            .for_campaign("campaign-id")
+            .personalize(&member(None), &config);
+        assert!(email.html.contains("https://mailer.example.com/c/r/"));
+        assert!(email
+            .html
+            .contains("https://mailer.example.com/track/open/"));
+        assert!(!email.html.contains("%%{"));
+    }
+
+    #[test]
+    fn compiled_email_fills_member_values() {
+        let config = config();
+        let email = compile_email(&post("Hello", json!({})), &config, &settings())
+            .unwrap()
+            .personalize(&member(None), &config);
+        assert_eq!(email.subject, "Hello for you");
+        assert!(email
+            .html
+            .contains("<p>Hi there, you're jamie@example.com.</p>"));
+
+        let email = compile_email(&post("Hello", json!({})), &config, &settings())
+            .unwrap()
+            .personalize(&member(Some("Jamie & Sam")), &config);
+        assert_eq!(email.subject, "Hello for Jamie");
+        assert!(email
+            .html
+            .contains("<p>Hi Jamie, you're jamie@example.com.</p>"));
+    }
 }
@@ -0,0 +1,524 @@
+use lol_html::html_content::{ContentType, Element};
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::escape_html;
+
+/// Context the card rewriter needs to build links and colors.
+pub struct CardOptions<'a> {
+    pub post_url: &'a str,
+    pub accent_color: &'a str,
+}
+
+// Inline styles applied to card elements that only need restyling. Email clients
+// ignore the site CSS these cards rely on, so everything has to be inlined.
+const CARD_STYLES: &[(&str, &str)] = &[
+    // Bookmark
+    (".kg-bookmark-card", "margin:0 0 1.5em 0;"),
+    (
+        ".kg-bookmark-container",
+        "display:block;border:1px solid #e5eff5;border-radius:3px;color:#15212a;text-decoration:none;overflow:hidden;",
+    ),
+    (".kg-bookmark-content", "display:block;padding:16px;"),
+    (
+        ".kg-bookmark-title",
+        "font-size:15px;font-weight:600;line-height:1.4;color:#15212a;",
+    ),
+    (
+        ".kg-bookmark-description",
+        "margin-top:6px;font-size:13px;line-height:1.5;color:#738a94;max-height:40px;overflow:hidden;",
+    ),
+    (
+        ".kg-bookmark-metadata",
+        "margin-top:12px;font-size:13px;color:#15212a;",
+    ),
+    (
+        ".kg-bookmark-icon",
+        "display:inline-block;width:20px;height:20px;margin:0 6px 0 0;vertical-align:middle;",
+    ),
+    (".kg-bookmark-author", "vertical-align:middle;"),
+    (".kg-bookmark-publisher", "vertical-align:middle;color:#738a94;"),
+    (".kg-bookmark-thumbnail", "display:block;"),
+    (
+        ".kg-bookmark-thumbnail img",
+        "display:block;width:100%;height:auto;margin:0;",
+    ),
+    // Callout
+    (
+        ".kg-callout-emoji",
+        "display:inline-block;padding-right:12px;font-size:20px;line-height:1.4;vertical-align:top;",
+    ),
+    (".kg-callout-text", "display:inline;font-size:16px;line-height:1.6;"),
+    // Toggle
+    (
+        ".kg-toggle-card",
+        "margin:0 0 1.5em 0;padding:20px;border:1px solid #e5eff5;border-radius:3px;",
+    ),
+    (
+        ".kg-toggle-heading-text",
+        "margin:0 0 8px 0;font-size:17px;font-weight:600;line-height:1.4;",
+    ),
+    (".kg-toggle-content", "display:block;"),
+    // Product
+    (".kg-product-card", "margin:0 0 1.5em 0;"),
+    (
+        ".kg-product-card-container",
+        "padding:20px;border:1px solid #e5eff5;border-radius:5px;",
+    ),
+    (
+        ".kg-product-card-image",
+        "display:block;width:100%;height:auto;margin:0 0 16px 0;",
+    ),
+    (".kg-product-card-title", "margin:0;font-size:20px;font-weight:700;"),
+    (".kg-product-card-rating", "margin-top:6px;font-size:18px;"),
+    (".kg-product-card-description", "margin-top:12px;"),
+    // Gallery
+    (".kg-gallery-card", "margin:0 0 1.5em 0;"),
+    (".kg-gallery-container", "display:block;"),
+    (".kg-gallery-row", "display:block;"),
+    (".kg-gallery-image", "display:block;margin:0 0 8px 0;"),
+    (
+        ".kg-gallery-image img",
+        "display:block;width:100%;height:auto;margin:0;",
+    ),
+    // Audio
+    (
+        ".kg-audio-card",
+        "margin:0 0 1.5em 0;padding:16px;border:1px solid #e5eff5;border-radius:3px;",
+    ),
+    (
+        "img.kg-audio-thumbnail",
+        "display:inline-block;width:60px;height:60px;margin:0 12px 0 0;border-radius:2px;vertical-align:middle;",
+    ),
+    (
+        ".kg-audio-player-container",
+        "display:inline-block;vertical-align:middle;",
+    ),
+    (".kg-audio-title", "font-size:16px;font-weight:600;"),
+    // Video
+    (".kg-video-card", "margin:0 0 1.5em 0;"),
+    // Embed
+    (".kg-embed-card", "margin:0 0 1.5em 0;"),
+    (
+        ".kg-embed-card blockquote",
+        "margin:0;padding:16px 20px;border:1px solid #e5eff5;border-radius:5px;",
+    ),
+    // File
+    (".kg-file-card", "margin:0 0 1.5em 0;"),
+    (
+        ".kg-file-card-container",
+        "display:block;padding:12px 16px;border:1px solid #e5eff5;border-radius:3px;color:#15212a;text-decoration:none;",
+    ),
+    (".kg-file-card-title", "font-size:16px;font-weight:600;"),
+    (".kg-file-card-caption", "font-size:14px;color:#738a94;"),
+    (
+        ".kg-file-card-metadata",
+        "margin-top:4px;font-size:13px;color:#738a94;",
+    ),
+    (".kg-file-card-filename", "display:inline;"),
+    (".kg-file-card-filesize", "display:inline;"),
+    // Captions shared by image, gallery, video and embed cards
+    (
+        ".kg-card figcaption",
+        "margin-top:10px;font-size:14px;color:#738a94;text-align:center;",
+    ),
+];
+
+/// Rewrites Ghost's Koenig cards (`kg-*`) in post HTML into static markup that
+/// renders in email clients without the site's CSS and JavaScript.
+pub fn render_cards(html: &str, options: &CardOptions) -> anyhow::Result<String> {
+    let post_url = escape_html(options.post_url);
+    let accent_color = escape_html(options.accent_color);
+    let button_style = format!(
+        "display:inline-block;padding:12px 24px;border-radius:5px;background:{};color:#ffffff;font-weight:600;text-decoration:none;",
+        accent_color
+    );
+    let video_thumbnail: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
+    let card_thumbnail = video_thumbnail.clone();
+
+    let mut handlers = vec![
+        // Scripts, inline SVG icons and controls never work in email
+        element!(".kg-card script, .kg-card svg, .kg-card button", |el| {
+            el.remove();
+            Ok(())
+        }),
+        // Callouts get their background from a color class
+        element!(".kg-callout-card", |el| {
+            let background = callout_background(&el.get_attribute("class").unwrap_or_default());
+            add_style(
+                el,
+                &format!(
+                    "margin:0 0 1.5em 0;padding:20px 28px;border-radius:3px;background:{};",
+                    background
+                ),
+            );
+            Ok(())
+        }),
+        // Toggles are always rendered expanded
+        element!(".kg-toggle-card", |el| {
+            el.remove_attribute("data-kg-toggle-state");
+            Ok(())
+        }),
+        element!(".kg-button-card", |el| {
+            let class = el.get_attribute("class").unwrap_or_default();
+            let align = if class.contains("kg-align-left") {
+                "left"
+            } else {
+                "center"
+            };
+            add_style(el, &format!("margin:0 0 1.5em 0;text-align:{};", align));
+            Ok(())
+        }),
+        element!(".kg-button-card a, .kg-product-card-button", |el| {
+            add_style(el, &button_style);
+            Ok(())
+        }),
+        // Rating stars are SVGs, swap them for text glyphs
+        element!(".kg-product-card-rating-star", |el| {
+            let class = el.get_attribute("class").unwrap_or_default();
+            let star = if class.contains("kg-product-card-rating-active") {
+                "★"
+            } else {
+                "☆"
+            };
+            el.replace(
+                &format!(r#"<span style="color:{};">{}</span>"#, accent_color, star),
+                ContentType::Html,
+            );
+            Ok(())
+        }),
+        // Galleries are stacked into a single column of full-width images
+        element!(".kg-gallery-image img", |el| {
+            el.remove_attribute("height");
+            el.remove_attribute("loading");
+            el.set_attribute("width", "600")?;
+            Ok(())
+        }),
+        // Audio players become a link back to the post
+        element!(
+            ".kg-audio-card audio, div.kg-audio-thumbnail, .kg-audio-hide",
+            |el| {
+                el.remove();
+                Ok(())
+            }
+        ),
+        element!(".kg-audio-player", |el| {
+            el.replace(
+                &format!(
+                    r#"<a href="{}" style="display:inline-block;margin-top:4px;font-size:14px;">▶ Listen to the audio</a>"#,
+                    post_url
+                ),
+                ContentType::Html,
+            );
+            Ok(())
+        }),
+        // Videos become their thumbnail linking to the post
+        element!(".kg-video-card", move |el| {
+            let thumbnail = el
+                .get_attribute("data-kg-custom-thumbnail")
+                .filter(|t| !t.is_empty())
+                .or_else(|| el.get_attribute("data-kg-thumbnail"))
+                .filter(|t| !t.is_empty());
+            *card_thumbnail.borrow_mut() = thumbnail;
+            el.remove_attribute("data-kg-thumbnail");
+            el.remove_attribute("data-kg-custom-thumbnail");
+            Ok(())
+        }),
+        element!(".kg-video-container", |el| {
+            let thumbnail_html = video_thumbnail.borrow().as_ref().map_or(String::new(), |t| {
+                format!(
+                    r#"<a href="{}" style="display:block;"><img src="{}" alt="Play video" style="display:block;width:100%;height:auto;margin:0;border:0;"></a>"#,
+                    post_url,
+                    escape_html(t)
+                )
+            });
+            el.replace(
+                &format!(
+                    r#"{}<p style="margin:8px 0 0 0;text-align:center;font-size:14px;"><a href="{}">▶ Watch the video</a></p>"#,
+                    thumbnail_html, post_url
+                ),
+                ContentType::Html,
+            );
+            Ok(())
+        }),
+        // Embedded players become a linked thumbnail where the provider has one
+        element!(".kg-embed-card iframe", |el| {
+            let src = el.get_attribute("src").unwrap_or_default();
+            el.replace(&embed_replacement(&src, &post_url), ContentType::Html);
+            Ok(())
+        }),
+        element!(".kg-file-card-container", |el| {
+            el.remove_attribute("download");
+            Ok(())
+        }),
+        element!(".kg-file-card-icon", |el| {
+            el.remove();
+            Ok(())
+        }),
+        element!(".kg-file-card-filesize, .kg-bookmark-publisher", |el| {
+            el.prepend(" • ", ContentType::Text);
+            Ok(())
+        }),
+    ];
+
+    handlers.extend(CARD_STYLES.iter().map(|(selector, style)| {
+        element!(selector, move |el| {
+            add_style(el, style);
+            Ok(())
+        })
+    }));
+
+    let html = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: handlers,
+            ..RewriteStrSettings::default()
+        },
+    )?;
+
+    Ok(html)
+}
+
+fn add_style(el: &mut Element, style: &str) {
+    let style = match el.get_attribute("style") {
+        Some(existing) if !existing.is_empty() => {
+            format!("{};{}", existing.trim_end_matches(';'), style)
+        }
+        _ => style.to_string(),
+    };
+    // Only fails for invalid attribute names, "style" is always valid
+    let _ = el.set_attribute("style", &style);
+}
+
+fn callout_background(class: &str) -> &'static str {
+    [
+        ("kg-callout-card-white", "#ffffff"),
+        ("kg-callout-card-blue", "#e4f5fc"),
+        ("kg-callout-card-green", "#e7f6e9"),
+        ("kg-callout-card-yellow", "#fdf4e2"),
+        ("kg-callout-card-red", "#f9e5e5"),
+        ("kg-callout-card-pink", "#fbe9f5"),
+        ("kg-callout-card-purple", "#f0ebfd"),
+    ]
+    .iter()
+    .find(|(name, _)| class.split_whitespace().any(|c| c == *name))
+    .map_or("#eff1f3", |(_, color)| color)
+}
+
+fn embed_replacement(src: &str, post_url: &str) -> String {
+    let src = src.trim();
+    let src = src
+        .strip_prefix("https:")
+        .or(src.strip_prefix("http:"))
+        .unwrap_or(src);
+
+    let youtube_id = [
+        "//www.youtube.com/embed/",
+        "//youtube.com/embed/",
+        "//www.youtube-nocookie.com/embed/",
+    ]
+    .iter()
+    .find_map(|prefix| src.strip_prefix(prefix))
+    .map(|rest| rest.split(['?', '/', '&']).next().unwrap_or_default())
+    .filter(|id| !id.is_empty());
+
+    if let Some(id) = youtube_id {
+        let id = escape_html(id);
+        return format!(
+            r#"<a href="https://www.youtube.com/watch?v={id}" style="display:block;"><img src="https://img.youtube.com/vi/{id}/hqdefault.jpg" alt="Watch on YouTube" style="display:block;width:100%;height:auto;margin:0;border:0;"></a>"#
+        );
+    }
+
+    let vimeo_id = src
+        .strip_prefix("//player.vimeo.com/video/")
+        .map(|rest| rest.split(['?', '/', '&']).next().unwrap_or_default())
+        .filter(|id| !id.is_empty());
+
+    let (link, label) = match vimeo_id {
+        Some(id) => (
+            format!("https://vimeo.com/{}", escape_html(id)),
+            "Watch on Vimeo",
+        ),
+        None => (post_url.to_string(), "View the embedded content"),
+    };
+
+    format!(
+        r#"<p style="margin:0;text-align:center;"><a href="{}">▶ {}</a></p>"#,
+        link, label
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POST_URL: &str = "https://example.com/post/";
+
+    fn render(html: &str) -> String {
+        render_cards(
+            html,
+            &CardOptions {
+                post_url: POST_URL,
+                accent_color: "#ff1a75",
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn colors_callouts_by_class() {
+        let html = render(
+            r#"<div class="kg-card kg-callout-card kg-callout-card-blue"><div class="kg-callout-emoji">💡</div><div class="kg-callout-text">Note</div></div>"#,
+        );
+        assert!(html.contains(r#"class="kg-card kg-callout-card kg-callout-card-blue" style="margin:0 0 1.5em 0;padding:20px 28px;border-radius:3px;background:#e4f5fc;""#));
+        assert!(html.contains(r#"<div class="kg-callout-text" style="display:inline;font-size:16px;line-height:1.6;">Note</div>"#));
+
+        let html = render(r#"<div class="kg-card kg-callout-card kg-callout-card-accent"></div>"#);
+        assert!(html.contains("background:#eff1f3;"));
+    }
+
+    #[test]
+    fn expands_toggles() {
+        let html = render(
+            r#"<div class="kg-card kg-toggle-card" data-kg-toggle-state="close"><div class="kg-toggle-heading"><h4 class="kg-toggle-heading-text">Question</h4><button class="kg-toggle-card-icon"><svg></svg></button></div><div class="kg-toggle-content"><p>Answer</p></div></div>"#,
+        );
+        assert!(!html.contains("data-kg-toggle-state"));
+        assert!(!html.contains("<button"));
+        assert!(!html.contains("<svg"));
+        assert!(html.contains(
+            r#"<div class="kg-toggle-content" style="display:block;"><p>Answer</p></div>"#
+        ));
+    }
+
+    #[test]
+    fn keeps_toggles_without_content() {
+        let html = render(
+            r#"<div class="kg-card kg-toggle-card" data-kg-toggle-state="close"><div class="kg-toggle-heading"><h4 class="kg-toggle-heading-text">Question</h4></div></div>"#,
+        );
+        assert_eq!(
+            html,
+            r#"<div class="kg-card kg-toggle-card" style="margin:0 0 1.5em 0;padding:20px;border:1px solid #e5eff5;border-radius:3px;"><div class="kg-toggle-heading"><h4 class="kg-toggle-heading-text" style="margin:0 0 8px 0;font-size:17px;font-weight:600;line-height:1.4;">Question</h4></div></div>"#
+        );
+    }
+
+    #[test]
+    fn styles_buttons_with_the_accent_color() {
+        let html = render(
+            r#"<div class="kg-card kg-button-card kg-align-left"><a href="https://example.com/" class="kg-btn kg-btn-accent">Go</a></div>"#,
+        );
+        assert!(html.contains("margin:0 0 1.5em 0;text-align:left;"));
+        assert!(html.contains("background:#ff1a75;color:#ffffff;"));
+
+        let html = render(
+            r#"<div class="kg-card kg-button-card kg-align-center"><a href="/">Go</a></div>"#,
+        );
+        assert!(html.contains("text-align:center;"));
+    }
+
+    #[test]
+    fn replaces_product_rating_stars() {
+        let html = render(
+            r#"<div class="kg-card kg-product-card"><div class="kg-product-card-rating"><span class="kg-product-card-rating-active kg-product-card-rating-star"><svg></svg></span><span class="kg-product-card-rating-star"><svg></svg></span></div><a href="/buy" class="kg-product-card-button">Buy</a></div>"#,
+        );
+        assert!(html.contains(
+            r#"<span style="color:#ff1a75;">★</span><span style="color:#ff1a75;">☆</span>"#
+        ));
+        assert!(html.contains(
+            r#"class="kg-product-card-button" style="display:inline-block;padding:12px 24px;"#
+        ));
+        assert!(!html.contains("<svg"));
+    }
+
+    #[test]
+    fn stacks_gallery_images() {
+        let html = render(
+            r#"<figure class="kg-card kg-gallery-card"><div class="kg-gallery-container"><div class="kg-gallery-row"><div class="kg-gallery-image"><img src="/a.jpg" width="1200" height="800" loading="lazy"></div></div></div></figure>"#,
+        );
+        assert!(html.contains(r#"<img src="/a.jpg" width="600" style="display:block;width:100%;height:auto;margin:0;">"#));
+    }
+
+    #[test]
+    fn links_audio_to_the_post() {
+        let html = render(
+            r#"<div class="kg-card kg-audio-card"><img src="/t.jpg" class="kg-audio-thumbnail"><div class="kg-audio-thumbnail placeholder"><svg></svg></div><div class="kg-audio-player-container"><audio src="/a.mp3"></audio><div class="kg-audio-title">Episode</div><div class="kg-audio-player"><button>Play</button></div><input class="kg-audio-hide"></div></div>"#,
+        );
+        assert!(!html.contains("<audio"));
+        assert!(!html.contains("placeholder"));
+        assert!(!html.contains("kg-audio-hide"));
+        assert!(html.contains(r#"<a href="https://example.com/post/" style="display:inline-block;margin-top:4px;font-size:14px;">▶ Listen to the audio</a>"#));
+        assert!(html.contains(r#"<img src="/t.jpg" class="kg-audio-thumbnail" style="display:inline-block;width:60px;"#));
+    }
+
+    #[test]
+    fn replaces_videos_with_their_thumbnail() {
+        let html = render(
+            r#"<figure class="kg-card kg-video-card" data-kg-thumbnail="/thumb.jpg" data-kg-custom-thumbnail=""><div class="kg-video-container"><video src="/v.mp4"></video></div></figure>"#,
+        );
+        assert!(!html.contains("<video"));
+        assert!(!html.contains("data-kg-thumbnail"));
+        assert!(html.contains(r#"<a href="https://example.com/post/" style="display:block;"><img src="/thumb.jpg" alt="Play video""#));
+        assert!(html.contains("▶ Watch the video"));
+    }
+
+    #[test]
+    fn links_embeds_to_their_provider() {
+        let embed = |src: &str| {
+            render(&format!(
+                r#"<figure class="kg-card kg-embed-card"><iframe src="{}"></iframe></figure>"#,
+                src
+            ))
+        };
+
+        let html = embed("https://www.youtube.com/embed/abc123?feature=oembed");
+        assert!(html.contains(r#"<a href="https://www.youtube.com/watch?v=abc123""#));
+        assert!(html.contains("https://img.youtube.com/vi/abc123/hqdefault.jpg"));
+        assert!(!html.contains("<iframe"));
+
+        let html = embed("//player.vimeo.com/video/42?h=1");
+        assert!(html.contains(r#"<a href="https://vimeo.com/42">▶ Watch on Vimeo</a>"#));
+
+        for src in [
+            "https://open.spotify.com/embed/track/1",
+            "https://www.youtube.com/embed/",
+            "",
+        ] {
+            let html = embed(src);
+            assert!(
+                html.contains(
+                    r#"<a href="https://example.com/post/">▶ View the embedded content</a>"#
+                ),
+                "{}",
+                src
+            );
+            assert!(!html.contains("<iframe"));
+        }
+    }
+
+    #[test]
+    fn styles_file_cards() {
+        let html = render(
+            r#"<div class="kg-card kg-file-card"><a class="kg-file-card-container" href="/f.pdf" download><div class="kg-file-card-contents"><div class="kg-file-card-title">Report</div><div class="kg-file-card-metadata"><div class="kg-file-card-filename">f.pdf</div><div class="kg-file-card-filesize">1 MB</div></div></div><div class="kg-file-card-icon"><svg></svg></div></a></div>"#,
+        );
+        assert!(!html.contains("download"));
+        assert!(!html.contains("kg-file-card-icon"));
+        assert!(html.contains(
+            r#"<div class="kg-file-card-filesize" style="display:inline;"> • 1 MB</div>"#
+        ));
+    }
+
+    #[test]
+    fn styles_bookmarks() {
+        let html = render(
+            r#"<figure class="kg-card kg-bookmark-card"><a class="kg-bookmark-container" href="https://other.example/"><div class="kg-bookmark-content"><div class="kg-bookmark-title">Title</div><div class="kg-bookmark-metadata"><span class="kg-bookmark-author">Author</span><span class="kg-bookmark-publisher">Site</span></div></div></a></figure>"#,
+        );
+        assert!(html.contains(r#"<span class="kg-bookmark-publisher" style="vertical-align:middle;color:#738a94;"> • Site</span>"#));
+        assert!(html.contains(r#"<a class="kg-bookmark-container" href="https://other.example/" style="display:block;"#));
+    }
+
+    #[test]
+    fn leaves_other_markup_alone() {
+        let html = r#"<p>Plain <a href="/x">text</a></p><script>keep()</script>"#;
+        assert_eq!(render(html), html);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::config::Config;
+use crate::ghost::{self, Post};
+use regex::{Captures, Regex};
+use std::sync::OnceLock;
+
+use super::template::Slot;
+use super::{
+    accent_color, escape_html, finish, preheader_html, urls, CompiledEmail, NewsletterInfo,
+    SiteInfo,
+};
+
+/// `List-Id` header for digests, kept apart from the site's per-post newsletter
+/// so mail clients can filter them separately.
+pub fn digest_list_id(settings: &ghost::Settings) -> String {
+    let host = reqwest::Url::parse(&settings.url)
+        .ok()
+        .and_then(|url| url.host_str().map(|h| h.to_string()))
+        .unwrap_or_else(|| "localhost".to_string());
+
+    format!(
+        "\"{} digest\" <digest.{}>",
+        settings.title.replace('"', ""),
+        host
+    )
+}
+
+/// Renders a roundup of `posts` into a `CompiledEmail`, each post with its image,
+/// title, excerpt and a link to read it on the site. `{site_title}` and
+/// `{post_count}` are filled in the subject template.
+pub fn compile_digest(
+    posts: &[Post],
+    subject_template: &str,
+    config: &Config,
+    settings: &ghost::Settings,
+) -> anyhow::Result<CompiledEmail> {
+    let site = SiteInfo::new(settings, accent_color(settings));
+    let newsletter = NewsletterInfo::new(config);
+
+    let preheader = posts
+        .iter()
+        .map(|post| post.title.as_str())
+        .collect::<Vec<_>>()
+        .join(" · ");
+
+    let entries = posts
+        .iter()
+        .map(|post| entry_html(post, &settings.url))
+        .collect::<anyhow::Result<String>>()?;
+
+    let html = format!(
+        r#"
+        <!DOCTYPE html>
+        <html lang="en">
+        <head>
+            <meta charset="utf-8">
+            <meta name="viewport" content="width=device-width, initial-scale=1">
+            <style>
+                body, div, p, h1, h2 {{
+                    margin: 0;
+                    padding: 0;
+                }}
+
+                body {{
+                    font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, Oxygen, Ubuntu, Cantarell, 'Open Sans', 'Helvetica Neue', sans-serif;
+                    line-height: 1.6;
+                    color: #333;
+                    background: #ffffff;
+                }}
+
+                .container {{
+                    max-width: 600px;
+                    margin: 0 auto;
+                    padding: 40px 20px;
+                }}
+
+                .header {{
+                    text-align: center;
+                    padding-bottom: 30px;
+                    border-bottom: 1px solid #e5eff5;
+                    margin-bottom: 30px;
+                }}
+
+                .header h1 {{
+                    font-size: 28px;
+                    font-weight: 600;
+                    margin-bottom: 10px;
+                }}
+
+                .header p {{
+                    color: #738a94;
+                    font-size: 15px;
+                }}
+
+                .entry {{
+                    padding-bottom: 30px;
+                    border-bottom: 1px solid #e5eff5;
+                    margin-bottom: 30px;
+                }}
+
+                .entry-image {{
+                    width: 100%;
+                    height: auto;
+                    border-radius: 5px;
+                    margin-bottom: 15px;
+                }}
+
+                .entry-title {{
+                    font-size: 22px;
+                    line-height: 1.3;
+                    font-weight: 700;
+                    margin-bottom: 8px;
+                }}
+
+                .entry-title a {{
+                    color: #15212a;
+                }}
+
+                .entry-meta {{
+                    font-size: 14px;
+                    color: #738a94;
+                    margin-bottom: 10px;
+                }}
+
+                .entry-excerpt {{
+                    font-size: 16px;
+                    margin-bottom: 10px;
+                }}
+
+                .footer {{
+                    margin-top: 20px;
+                    text-align: center;
+                    font-size: 14px;
+                    color: #738a94;
+                }}
+
+                a {{
+                    color: {color};
+                    text-decoration: none;
+                }}
+            </style>
+        </head>
+        <body>
+            {preheader_html}
+            <div class="container">
+                <div class="header">
+                    <h1>{site_title}</h1>
+                    <p>{post_count} new {posts_noun} since the last digest</p>
+                </div>
+
+                {entries}
+
+                <div class="footer">
+                    <p>You received this digest because you signed up for a roundup of {site_title}.</p>
+                    <p>
+                        <a href="{subscription_link}">Manage subscription</a> •
+                        <a href="{unsubscribe_link}">Unsubscribe</a>
+                    </p>
+                </div>
+            </div>
+            {open_pixel}
+        </body>
+        </html>
+    "#,
+        color = site.color,
+        preheader_html = preheader_html(&preheader),
+        site_title = escape_html(&site.title),
+        post_count = posts.len(),
+        posts_noun = if posts.len() == 1 { "post" } else { "posts" },
+        entries = entries,
+        subscription_link = newsletter.subscription_link,
+        unsubscribe_link = newsletter.unsubscribe_link,
+        open_pixel = if config.open_tracking {
+            Slot::OpenPixel.marker()
+        } else {
+            String::new()
+        }
+    );
+
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    let placeholder = PLACEHOLDER.get_or_init(|| {
+        Regex::new(r"\{(site_title|post_count)\}").expect("digest placeholder regex is valid")
+    });
+    let post_count = posts.len().to_string();
+    let subject = placeholder.replace_all(subject_template, |caps: &Captures| match &caps[1] {
+        "site_title" => settings.title.as_str(),
+        _ => post_count.as_str(),
+    });
+
+    finish(html, &subject, "digest", config, settings)
+}
+
+/// A post's entry in the digest. Only the post's summary is shown, so its body
+/// is never rendered.
+fn entry_html(post: &Post, site_url: &str) -> anyhow::Result<String> {
+    let image = match &post.feature_image {
+        Some(url) => format!(
+            r#"<a href="{}"><img src="{}" alt="{}" class="entry-image"></a>"#,
+            post.url,
+            urls::absolutize(site_url, url)?,
+            escape_html(post.feature_image_alt.as_deref().unwrap_or(""))
+        ),
+        None => String::new(),
+    };
+
+    Ok(format!(
+        r#"
+                <div class="entry">
+                    {image}
+                    <h2 class="entry-title"><a href="{url}">{title}</a></h2>
+                    <div class="entry-meta">{author} · {reading_time} min read</div>
+                    <p class="entry-excerpt">{excerpt}</p>
+                    <a href="{url}">Read more →</a>
+                </div>
+"#,
+        image = image,
+        url = post.url,
+        title = escape_html(&post.title),
+        author = escape_html(&post.primary_author.name),
+        reading_time = post.reading_time,
+        excerpt = escape_html(&post.excerpt),
+    ))
+}
@@ -0,0 +1,104 @@
+use crate::ghost::Member;
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Matches Ghost's personalization tags, e.g. `{first_name}` or
+/// `{first_name, "there"}`, as well as the `%%{slot}%%` markers the renderer uses
+/// for per-recipient values like the unsubscribe link. The editor may store the
+/// fallback quotes HTML-encoded or typographic, so all variants are accepted.
+pub fn merge_tag_regex() -> &'static Regex {
+    static MERGE_TAG: OnceLock<Regex> = OnceLock::new();
+    MERGE_TAG.get_or_init(|| {
+        Regex::new(
+            r#"%%\{([a-z0-9_]+)\}%%|\{\s*(first_name|name|email|uuid)\s*(?:,\s*(?:"|&quot;|“)(.*?)(?:"|&quot;|”)\s*)?\}"#,
+        )
+        .expect("merge tag regex is valid")
+    })
+}
+
+/// Resolves a single merge tag field for a member, `None` when the member has no
+/// value for it.
+pub fn member_field(member: &Member, field: &str) -> Option<String> {
+    let value = match field {
+        "first_name" => member
+            .name
+            .as_deref()
+            .and_then(|name| name.split_whitespace().next())
+            .map(|s| s.to_string()),
+        "name" => member.name.clone(),
+        "email" => Some(member.email.clone()),
+        "uuid" => Some(member.uuid.clone()),
+        _ => None,
+    };
+    value.filter(|v| !v.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(name: Option<&str>) -> Member {
+        Member {
+            id: "id".to_string(),
+            uuid: "uuid".to_string(),
+            email: "jamie@example.com".to_string(),
+            name: name.map(|name| name.to_string()),
+            status: "free".to_string(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            other: serde_json::Value::Null,
+        }
+    }
+
+    fn fallback(tag: &str) -> Option<String> {
+        let caps = merge_tag_regex().captures(tag)?;
+        Some(caps.get(3).map_or("", |m| m.as_str()).to_string())
+    }
+
+    #[test]
+    fn matches_every_fallback_quote_form() {
+        assert_eq!(
+            fallback(r#"{first_name, "there"}"#).as_deref(),
+            Some("there")
+        );
+        assert_eq!(
+            fallback("{first_name, &quot;there&quot;}").as_deref(),
+            Some("there")
+        );
+        assert_eq!(fallback("{first_name, “there”}").as_deref(), Some("there"));
+        assert_eq!(fallback("{ first_name }").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn matches_slot_markers_but_not_unknown_fields() {
+        let caps = merge_tag_regex().captures("%%{link_3}%%").unwrap();
+        assert_eq!(caps.get(1).map(|m| m.as_str()), Some("link_3"));
+        assert!(merge_tag_regex().captures("{last_name}").is_none());
+    }
+
+    #[test]
+    fn resolves_member_fields() {
+        let member = member(Some("Jamie  Larson"));
+        assert_eq!(
+            member_field(&member, "first_name").as_deref(),
+            Some("Jamie")
+        );
+        assert_eq!(
+            member_field(&member, "name").as_deref(),
+            Some("Jamie  Larson")
+        );
+        assert_eq!(
+            member_field(&member, "email").as_deref(),
+            Some("jamie@example.com")
+        );
+        assert_eq!(member_field(&member, "uuid").as_deref(), Some("uuid"));
+        assert_eq!(member_field(&member, "last_name"), None);
+    }
+
+    #[test]
+    fn blank_names_have_no_value() {
+        assert_eq!(member_field(&member(None), "first_name"), None);
+        assert_eq!(member_field(&member(Some("  ")), "first_name"), None);
+        assert_eq!(member_field(&member(Some("")), "name"), None);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::ghost::Member;
+
+use super::escape_html;
+use super::personalize::{member_field, merge_tag_regex};
+
+/// Per-recipient values a template can ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    UnsubscribeLink,
+    OpenPixel,
+    /// The n-th link of the email, tracked or not
+    TrackedLink(usize),
+}
+
+impl Slot {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unsubscribe_link" => Some(Slot::UnsubscribeLink),
+            "open_pixel" => Some(Slot::OpenPixel),
+            _ => name
+                .strip_prefix("link_")
+                .and_then(|index| index.parse().ok())
+                .map(Slot::TrackedLink),
+        }
+    }
+
+    /// Marker to place in rendered output where the slot's value should go.
+    pub fn marker(self) -> String {
+        match self {
+            Slot::UnsubscribeLink => "%%{unsubscribe_link}%%".to_string(),
+            Slot::OpenPixel => "%%{open_pixel}%%".to_string(),
+            Slot::TrackedLink(index) => format!("%%{{link_{}}}%%", index),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    Static(String),
+    Field { name: String, fallback: String },
+    Slot(Slot),
+}
+
+/// Values that differ between recipients of the same email.
+pub struct RecipientValues<'a> {
+    pub member: &'a Member,
+    pub unsubscribe_link: String,
+    /// Tracking pixel `<img>` tag, if the email is tracked
+    pub open_pixel: Option<String>,
+    /// Targets of the email's links, indexed by `Slot::TrackedLink`
+    pub links: Vec<String>,
+}
+
+/// Output rendered once per campaign, split into static text and the
+/// per-recipient merge tags and slots inside it.
+#[derive(Debug)]
+pub struct Template {
+    segments: Vec<Segment>,
+    static_len: usize,
+    escape: bool,
+}
+
+impl Template {
+    /// Splits `source` on merge tags and slot markers. With `escape` set, member
+    /// values are HTML-escaped when rendered.
+    pub fn compile(source: &str, escape: bool) -> Self {
+        let mut segments = Vec::new();
+        let mut last = 0;
+
+        for caps in merge_tag_regex().captures_iter(source) {
+            let whole = caps.get(0).expect("capture 0 is always present");
+            let segment = match (caps.get(1), caps.get(2)) {
+                (Some(slot), _) => Slot::from_name(slot.as_str()).map(Segment::Slot),
+                (None, Some(field)) => {
+                    let fallback = caps.get(3).map_or("", |m| m.as_str());
+                    Some(Segment::Field {
+                        name: field.as_str().to_string(),
+                        // HTML fallbacks come straight from the post and are already escaped
+                        fallback: if escape {
+                            fallback.to_string()
+                        } else {
+                            fallback.replace("&quot;", "\"")
+                        },
+                    })
+                }
+                _ => None,
+            };
+
+            // Unknown slot names are left in the output untouched
+            let Some(segment) = segment else {
+                continue;
+            };
+
+            if whole.start() > last {
+                segments.push(Segment::Static(source[last..whole.start()].to_string()));
+            }
+            segments.push(segment);
+            last = whole.end();
+        }
+
+        if last < source.len() {
+            segments.push(Segment::Static(source[last..].to_string()));
+        }
+
+        let static_len = segments
+            .iter()
+            .map(|s| match s {
+                Segment::Static(text) => text.len(),
+                _ => 0,
+            })
+            .sum();
+
+        Template {
+            segments,
+            static_len,
+            escape,
+        }
+    }
+
+    pub fn render(&self, values: &RecipientValues) -> String {
+        // Leave room for the per-recipient values so most renders never reallocate
+        let mut output = String::with_capacity(self.static_len + 256);
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Static(text) => output.push_str(text),
+                Segment::Field { name, fallback } => match member_field(values.member, name) {
+                    Some(value) if self.escape => output.push_str(&escape_html(&value)),
+                    Some(value) => output.push_str(&value),
+                    None => output.push_str(fallback),
+                },
+                Segment::Slot(Slot::UnsubscribeLink) => output.push_str(&values.unsubscribe_link),
+                Segment::Slot(Slot::OpenPixel) => {
+                    if let Some(pixel) = &values.open_pixel {
+                        output.push_str(pixel);
+                    }
+                }
+                Segment::Slot(Slot::TrackedLink(index)) => {
+                    if let Some(link) = values.links.get(*index) {
+                        output.push_str(link);
+                    }
+                }
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(name: Option<&str>) -> Member {
+        Member {
+            id: "id".to_string(),
+            uuid: "uuid".to_string(),
+            email: "jamie@example.com".to_string(),
+            name: name.map(|name| name.to_string()),
+            status: "free".to_string(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            other: serde_json::Value::Null,
+        }
+    }
+
+    fn render(source: &str, escape: bool, member: &Member) -> String {
+        Template::compile(source, escape).render(&RecipientValues {
+            member,
+            unsubscribe_link: "https://example.com/unsubscribe".to_string(),
+            open_pixel: Some("<img>".to_string()),
+            links: vec![
+                "https://a.example/".to_string(),
+                "https://b.example/".to_string(),
+            ],
+        })
+    }
+
+    #[test]
+    fn slot_markers_round_trip() {
+        for slot in [
+            Slot::UnsubscribeLink,
+            Slot::OpenPixel,
+            Slot::TrackedLink(12),
+        ] {
+            let marker = slot.marker();
+            let name = marker.trim_start_matches("%%{").trim_end_matches("}%%");
+            assert_eq!(Slot::from_name(name), Some(slot));
+        }
+        assert_eq!(Slot::from_name("link_x"), None);
+        assert_eq!(Slot::from_name("footer"), None);
+    }
+
+    #[test]
+    fn splits_static_text_fields_and_slots() {
+        let template = Template::compile(
+            r#"Hi {first_name, "there"}, <a href="%%{link_0}%%">read</a>"#,
+            true,
+        );
+        let kinds: Vec<&str> = template
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Static(_) => "static",
+                Segment::Field { .. } => "field",
+                Segment::Slot(_) => "slot",
+            })
+            .collect();
+        assert_eq!(kinds, ["static", "field", "static", "slot", "static"]);
+        assert_eq!(template.static_len, r#"Hi , <a href="">read</a>"#.len());
+    }
+
+    #[test]
+    fn fills_fields_and_slots() {
+        let html = render(
+            r#"Hi {first_name}! <a href="%%{link_1}%%">b</a> <a href="%%{unsubscribe_link}%%">x</a>%%{open_pixel}%%"#,
+            true,
+            &member(Some("Jamie Larson")),
+        );
+        assert_eq!(
+            html,
+            r#"Hi Jamie! <a href="https://b.example/">b</a> <a href="https://example.com/unsubscribe">x</a><img>"#
+        );
+    }
+
+    #[test]
+    fn falls_back_for_empty_names() {
+        for tag in [
+            r#"{first_name, "friend"}"#,
+            "{first_name, &quot;friend&quot;}",
+            "{first_name, “friend”}",
+        ] {
+            assert_eq!(render(tag, true, &member(Some(" "))), "friend");
+            assert_eq!(render(tag, false, &member(None)), "friend");
+        }
+        assert_eq!(render("Hi {first_name}", true, &member(None)), "Hi ");
+    }
+
+    #[test]
+    fn escapes_member_fields_in_html_only() {
+        let member = member(Some("<b>Jamie</b> & co"));
+        assert_eq!(
+            render("{name}", true, &member),
+            "&lt;b&gt;Jamie&lt;/b&gt; &amp; co"
+        );
+        assert_eq!(render("{name}", false, &member), "<b>Jamie</b> & co");
+    }
+
+    #[test]
+    fn keeps_fallbacks_as_written_for_each_output() {
+        // Already escaped in the post HTML, decoded for plain text
+        let tag = "{first_name, &quot;Mr &quot;Reader&quot;}";
+        assert_eq!(render(tag, true, &member(None)), "Mr &quot;Reader");
+        assert_eq!(
+            render(r#"{first_name, "a &amp; b"}"#, true, &member(None)),
+            "a &amp; b"
+        );
+        assert_eq!(
+            render("{first_name, “say &quot;hi&quot;”}", false, &member(None)),
+            r#"say "hi""#
+        );
+    }
+
+    #[test]
+    fn leaves_unknown_tags_and_slots_alone() {
+        let source = "{last_name} %%{footer}%% %%{link_5}%%";
+        assert_eq!(
+            render(source, true, &member(None)),
+            "{last_name} %%{footer}%% "
+        );
+    }
+
+    #[test]
+    fn skips_open_pixel_when_untracked() {
+        let html = Template::compile("a%%{open_pixel}%%b", true).render(&RecipientValues {
+            member: &member(None),
+            unsubscribe_link: String::new(),
+            open_pixel: None,
+            links: Vec::new(),
+        });
+        assert_eq!(html, "ab");
+    }
+}
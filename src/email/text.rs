@@ -0,0 +1,101 @@
+use lol_html::html_content::ContentType;
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use regex::Regex;
+use std::sync::OnceLock;
+
+// Elements that start a new line in plain text
+const BLOCK_ELEMENTS: &str =
+    "p, div, h1, h2, h3, h4, h5, h6, ul, ol, tr, blockquote, figure, figcaption, hr, table";
+
+/// Converts a rendered email into readable plain text: hidden content and styles
+/// are dropped, blocks become lines and links keep their target in brackets.
+pub fn html_to_text(html: &str) -> anyhow::Result<String> {
+    let marked = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("head, style, script", |el| {
+                    el.remove();
+                    Ok(())
+                }),
+                // The preheader and other content meant for the inbox preview only
+                element!("[style*='display:none'], [style*='display: none']", |el| {
+                    el.remove();
+                    Ok(())
+                }),
+                element!("a[href]", |el| {
+                    // Decoded here since inserted text is escaped again
+                    let href = el
+                        .get_attribute("href")
+                        .unwrap_or_default()
+                        .replace("&amp;", "&");
+                    if href.starts_with("http") {
+                        el.after(&format!(" ({})", href), ContentType::Text);
+                    }
+                    Ok(())
+                }),
+                // List items go on their own line, without a blank one between them
+                element!("li", |el| {
+                    el.before("\n", ContentType::Text);
+                    el.prepend("- ", ContentType::Text);
+                    Ok(())
+                }),
+                element!("br", |el| {
+                    el.after("\n", ContentType::Text);
+                    Ok(())
+                }),
+                element!(BLOCK_ELEMENTS, |el| {
+                    el.before("\n", ContentType::Text);
+                    el.after("\n", ContentType::Text);
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        },
+    )?;
+
+    let stripped = tag_regex().replace_all(&marked, "");
+    let decoded = decode_entities(&stripped);
+
+    // Collapse the indentation and blank lines the markup leaves behind
+    let mut text = String::with_capacity(decoded.len());
+    let mut blank_lines = 0;
+    for line in decoded.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+        if !text.is_empty() {
+            text.push_str(if blank_lines > 0 { "\n\n" } else { "\n" });
+        }
+        text.push_str(&line);
+        blank_lines = 0;
+    }
+
+    Ok(text)
+}
+
+fn tag_regex() -> &'static Regex {
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    TAG.get_or_init(|| Regex::new(r"(?s)<!--.*?-->|<[^>]*>").expect("tag regex is valid"))
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&#8199;", "")
+        .replace("&#65279;", "")
+        .replace("&#847;", "")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&rsquo;", "\u{2019}")
+        .replace("&lsquo;", "\u{2018}")
+        .replace("&rdquo;", "\u{201d}")
+        .replace("&ldquo;", "\u{201c}")
+        .replace("&mdash;", "\u{2014}")
+        .replace("&ndash;", "\u{2013}")
+        .replace("&hellip;", "\u{2026}")
+        .replace("&amp;", "&")
+}
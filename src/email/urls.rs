@@ -0,0 +1,348 @@
+use lol_html::html_content::Element;
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use reqwest::Url;
+
+use super::template::Slot;
+
+// Email bodies are 600px wide, so pick images that still look sharp on 2x displays
+const EMAIL_IMAGE_WIDTH: u32 = 1200;
+
+/// Rewrites every relative `href`, `src` and `srcset` in the post HTML against the
+/// site URL, and replaces responsive image sets with a single email-sized source.
+pub fn absolutize_urls(html: &str, site_url: &str) -> anyhow::Result<String> {
+    let base = site_base(site_url)?;
+
+    let html = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("[href]", |el| {
+                    rewrite_attribute(el, "href", &base)?;
+                    Ok(())
+                }),
+                element!("img[srcset]", |el| {
+                    let srcset = el.get_attribute("srcset").unwrap_or_default();
+                    // Images without a src don't match the handler below, so
+                    // resolve the picked candidate here
+                    if let Some(src) = pick_email_image(&srcset) {
+                        el.set_attribute("src", &absolutize_url(&base, &src))?;
+                    }
+                    el.remove_attribute("srcset");
+                    el.remove_attribute("sizes");
+                    Ok(())
+                }),
+                element!("[src]", |el| {
+                    rewrite_attribute(el, "src", &base)?;
+                    Ok(())
+                }),
+                element!("[srcset]", |el| {
+                    // Images already had their srcset collapsed above
+                    let Some(srcset) = el.get_attribute("srcset") else {
+                        return Ok(());
+                    };
+                    let rewritten = srcset
+                        .split(',')
+                        .map(|candidate| {
+                            let mut parts = candidate.split_whitespace();
+                            let url = parts.next().unwrap_or_default();
+                            let descriptor = parts.collect::<Vec<_>>().join(" ");
+                            let url = absolutize_url(&base, url);
+                            if descriptor.is_empty() {
+                                url
+                            } else {
+                                format!("{} {}", url, descriptor)
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    el.set_attribute("srcset", &rewritten)?;
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        },
+    )?;
+
+    Ok(html)
+}
+
+/// Appends `params` to the query of every web link pointing at the site, or at
+/// any site with `all_links`. Links that already carry UTM parameters keep them.
+pub fn add_utm_params(
+    html: &str,
+    site_url: &str,
+    params: &[(&str, &str)],
+    all_links: bool,
+) -> anyhow::Result<String> {
+    let site = site_base(site_url)?;
+
+    let html = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![element!("a[href]", |el| {
+                let href = el.get_attribute("href").unwrap_or_default();
+                // Attribute values are raw HTML, parse the URL a browser would follow
+                let Ok(mut url) = Url::parse(&href.trim().replace("&amp;", "&")) else {
+                    return Ok(());
+                };
+
+                let is_web = matches!(url.scheme(), "http" | "https");
+                let is_site = is_same_site(&site, &url);
+                let is_tagged = url.query_pairs().any(|(key, _)| key.starts_with("utm_"));
+                if !is_web || is_tagged || !(all_links || is_site) {
+                    return Ok(());
+                }
+
+                url.query_pairs_mut().extend_pairs(params);
+                el.set_attribute("href", &url.as_str().replace('&', "&amp;"))?;
+                Ok(())
+            })],
+            ..RewriteStrSettings::default()
+        },
+    )?;
+
+    Ok(html)
+}
+
+/// Whether `url` is on the site, ignoring a leading `www.` on either side.
+fn is_same_site(site: &Url, url: &Url) -> bool {
+    let host = |url: &Url| {
+        url.host_str()
+            .map(|host| host.trim_start_matches("www.").to_ascii_lowercase())
+    };
+    host(site).is_some() && host(site) == host(url) && url.path().starts_with(site.path())
+}
+
+/// Replaces the `href` of every trackable link with a `Slot::TrackedLink` marker,
+/// returning the original targets in slot order. Links to the same URL share a slot.
+pub fn extract_links(html: &str) -> anyhow::Result<(String, Vec<String>)> {
+    let mut links: Vec<String> = Vec::new();
+
+    let html = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![element!("a[href]", |el| {
+                let href = el.get_attribute("href").unwrap_or_default();
+                if !is_trackable(&href) {
+                    return Ok(());
+                }
+                let index = match links.iter().position(|link| *link == href) {
+                    Some(index) => index,
+                    None => {
+                        links.push(href);
+                        links.len() - 1
+                    }
+                };
+                el.set_attribute("href", &Slot::TrackedLink(index).marker())?;
+                Ok(())
+            })],
+            ..RewriteStrSettings::default()
+        },
+    )?;
+
+    Ok((html, links))
+}
+
+/// Web links only. Unsubscribe links must keep working without this service, and
+/// links carrying merge tags or slots are already per recipient.
+fn is_trackable(href: &str) -> bool {
+    let href = href.trim();
+    (href.starts_with("https://") || href.starts_with("http://"))
+        && !href.contains('{')
+        && !href.contains("/unsubscribe")
+}
+
+/// Resolves a single URL against the site URL, leaving absolute URLs untouched.
+pub fn absolutize(site_url: &str, url: &str) -> anyhow::Result<String> {
+    Ok(absolutize_url(&site_base(site_url)?, url))
+}
+
+fn site_base(site_url: &str) -> anyhow::Result<Url> {
+    // Without a trailing slash the last path segment of a subdirectory install
+    // would be dropped when joining
+    let base = format!("{}/", site_url.trim_end_matches('/'));
+    Url::parse(&base).map_err(|e| anyhow::anyhow!("Invalid site URL {}: {}", site_url, e))
+}
+
+fn absolutize_url(base: &Url, url: &str) -> String {
+    let trimmed = url.trim();
+
+    // In-document anchors and empty values are left alone
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return url.to_string();
+    }
+
+    match base.join(trimmed) {
+        Ok(absolute) => absolute.to_string(),
+        Err(_) => url.to_string(),
+    }
+}
+
+fn rewrite_attribute(el: &mut Element, name: &str, base: &Url) -> anyhow::Result<()> {
+    if let Some(value) = el.get_attribute(name) {
+        el.set_attribute(name, &absolutize_url(base, &value))?;
+    }
+    Ok(())
+}
+
+/// Picks the smallest `srcset` candidate that is at least `EMAIL_IMAGE_WIDTH` wide,
+/// falling back to the largest one available.
+fn pick_email_image(srcset: &str) -> Option<String> {
+    let mut candidates: Vec<(u32, &str)> = srcset
+        .split(',')
+        .filter_map(|candidate| {
+            let mut parts = candidate.split_whitespace();
+            let url = parts.next()?;
+            let width = parts.next()?.strip_suffix('w')?.parse().ok()?;
+            Some((width, url))
+        })
+        .collect();
+
+    candidates.sort_by_key(|(width, _)| *width);
+
+    candidates
+        .iter()
+        .find(|(width, _)| *width >= EMAIL_IMAGE_WIDTH)
+        .or(candidates.last())
+        .map(|(_, url)| url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absolutizes_image_picked_from_srcset() {
+        let html = r#"<img srcset="/content/images/size/w600/a.jpg 600w, /content/images/size/w1200/a.jpg 1200w">"#;
+        let html = absolutize_urls(html, "https://example.com").unwrap();
+        assert_eq!(
+            html,
+            r#"<img src="https://example.com/content/images/size/w1200/a.jpg">"#
+        );
+    }
+
+    const UTM: &[(&str, &str)] = &[("utm_source", "newsletter"), ("utm_medium", "email")];
+
+    fn tag(href: &str, site_url: &str, all_links: bool) -> String {
+        let html = add_utm_params(
+            &format!(r#"<a href="{}">x</a>"#, href),
+            site_url,
+            UTM,
+            all_links,
+        )
+        .unwrap();
+        html.strip_prefix(r#"<a href=""#)
+            .and_then(|html| html.strip_suffix(r#"">x</a>"#))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn detects_same_site_links() {
+        let site = site_base("https://www.example.com").unwrap();
+        let same = |url: &str| is_same_site(&site, &Url::parse(url).unwrap());
+        assert!(same("https://example.com/post/"));
+        assert!(same("http://WWW.Example.com/"));
+        assert!(!same("https://blog.example.com/"));
+        assert!(!same("https://example.org/"));
+        assert!(!same("mailto:someone@example.com"));
+
+        let site = site_base("https://example.com/blog").unwrap();
+        let same = |url: &str| is_same_site(&site, &Url::parse(url).unwrap());
+        assert!(same("https://example.com/blog/post/"));
+        assert!(!same("https://example.com/shop/"));
+    }
+
+    #[test]
+    fn tags_site_links() {
+        assert_eq!(
+            tag("https://example.com/post/", "https://example.com", false),
+            "https://example.com/post/?utm_source=newsletter&amp;utm_medium=email"
+        );
+        assert_eq!(
+            tag(
+                "https://example.com/blog/post/#top",
+                "https://www.example.com/blog/",
+                false
+            ),
+            "https://example.com/blog/post/?utm_source=newsletter&amp;utm_medium=email#top"
+        );
+    }
+
+    #[test]
+    fn keeps_existing_query_and_entities() {
+        assert_eq!(
+            tag(
+                "https://example.com/?a=1&amp;b=2",
+                "https://example.com",
+                false
+            ),
+            "https://example.com/?a=1&amp;b=2&amp;utm_source=newsletter&amp;utm_medium=email"
+        );
+    }
+
+    #[test]
+    fn leaves_tagged_and_non_web_links_alone() {
+        let site = "https://example.com";
+        for href in [
+            "https://example.com/?utm_source=twitter",
+            "https://example.com/?a=1&amp;utm_campaign=x",
+            "mailto:hello@example.com",
+            "#footnote",
+            "/relative/",
+        ] {
+            assert_eq!(tag(href, site, true), href);
+        }
+    }
+
+    #[test]
+    fn tags_other_sites_only_with_all_links() {
+        let site = "https://example.com";
+        assert_eq!(
+            tag("https://other.example/", site, false),
+            "https://other.example/"
+        );
+        assert_eq!(
+            tag("https://other.example/", site, true),
+            "https://other.example/?utm_source=newsletter&amp;utm_medium=email"
+        );
+        assert_eq!(
+            tag(
+                "https://example.com/shop/",
+                "https://example.com/blog",
+                false
+            ),
+            "https://example.com/shop/"
+        );
+    }
+
+    #[test]
+    fn numbers_tracked_links_by_first_appearance() {
+        let html = r#"<a href="https://a.example/">1</a><a href="https://b.example/">2</a><a href="https://a.example/">3</a>"#;
+        let (html, links) = extract_links(html).unwrap();
+        assert_eq!(links, ["https://a.example/", "https://b.example/"]);
+        assert_eq!(
+            html,
+            r#"<a href="%%{link_0}%%">1</a><a href="%%{link_1}%%">2</a><a href="%%{link_0}%%">3</a>"#
+        );
+    }
+
+    #[test]
+    fn leaves_untrackable_links_alone() {
+        let html = concat!(
+            r#"<a href="mailto:hello@example.com">a</a>"#,
+            r#"<a href="/relative/">b</a>"#,
+            r##"<a href="#top">c</a>"##,
+            r#"<a href="https://example.com/?u={uuid}">d</a>"#,
+            r#"<a href="%%{unsubscribe_link}%%">e</a>"#,
+            r#"<a href="https://example.com/unsubscribe/?uuid=1">f</a>"#,
+            r#"<a href=" https://a.example/ ">g</a>"#,
+        );
+        let (rewritten, links) = extract_links(html).unwrap();
+        assert_eq!(links, [" https://a.example/ "]);
+        assert_eq!(
+            rewritten,
+            html.replace(r#"" https://a.example/ ""#, r#""%%{link_0}%%""#)
+        );
+    }
+}
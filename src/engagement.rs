@@ -0,0 +1,163 @@
+use crate::{analytics::SendOutcome, config::Config, ghost, store::Store};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Prefix of the line kept up to date in the member's note
+const NOTE_PREFIX: &str = "Email activity:";
+
+// Ghost only shows an open rate once a member received this many emails
+const MIN_EMAILS_FOR_OPEN_RATE: u32 = 5;
+
+// Pause between members so a sync of many members stays under Ghost's rate limits
+const UPDATE_DELAY: Duration = Duration::from_millis(500);
+
+/// A member's email activity in the shape of Ghost's own member fields.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberEngagement {
+    pub email_count: u32,
+    pub email_opened_count: u32,
+    /// Percentage of emails opened, `None` until enough emails were sent
+    pub email_open_rate: Option<u32>,
+}
+
+impl MemberEngagement {
+    fn note_line(&self) -> String {
+        let rate = match self.email_open_rate {
+            Some(rate) => format!(" ({}%)", rate),
+            None => String::new(),
+        };
+        format!(
+            "{} {} received, {} opened{}",
+            NOTE_PREFIX, self.email_count, self.email_opened_count, rate
+        )
+    }
+}
+
+/// Aggregates every send in the store by Ghost member ID.
+pub fn member_engagement(store: &Store) -> HashMap<String, MemberEngagement> {
+    let mut engagement: HashMap<String, MemberEngagement> = HashMap::new();
+
+    store.read(|data| {
+        for send in data.sends.values() {
+            let member = engagement.entry(send.member_id.clone()).or_default();
+            member.email_count += 1;
+            member.email_opened_count += SendOutcome::of(send).opened as u32;
+        }
+    });
+
+    for member in engagement.values_mut() {
+        if member.email_count >= MIN_EMAILS_FOR_OPEN_RATE {
+            member.email_open_rate = Some(
+                (member.email_opened_count as f64 / member.email_count as f64 * 100.0).round()
+                    as u32,
+            );
+        }
+    }
+
+    engagement
+}
+
+/// Pushes engagement that changed since the last sync to Ghost.
+///
+/// Ghost treats its email activity fields as read-only in most versions and
+/// silently drops them or rejects the update, so when the saved member doesn't
+/// reflect the new counts they are written to a line of the member's note
+/// instead. Updates are spaced out to stay under Ghost's rate limits.
+pub async fn sync_to_ghost(config: &Config, store: &Store) {
+    let engagement = member_engagement(store);
+    let changed: Vec<(String, MemberEngagement)> = store.read(|data| {
+        engagement
+            .into_iter()
+            .filter(|(member_id, current)| data.ghost_engagement.get(member_id) != Some(current))
+            .collect()
+    });
+
+    if changed.is_empty() {
+        tracing::debug!("No member engagement changes to sync to Ghost");
+        return;
+    }
+    tracing::info!(
+        "Syncing email activity of {} members to Ghost",
+        changed.len()
+    );
+
+    for (member_id, current) in changed {
+        match push_member(config, &member_id, &current).await {
+            Ok(()) => store.update(|data| {
+                data.ghost_engagement.insert(member_id, current);
+            }),
+            Err(e) => tracing::error!(
+                "Failed to sync email activity of member {}: {}",
+                member_id,
+                e
+            ),
+        }
+        tokio::time::sleep(UPDATE_DELAY).await;
+    }
+}
+
+async fn push_member(
+    config: &Config,
+    member_id: &str,
+    engagement: &MemberEngagement,
+) -> anyhow::Result<()> {
+    let member =
+        match ghost::update_member(config, member_id, serde_json::to_value(engagement)?).await {
+            Ok(member) => {
+                let saved = member.other.get("email_count").and_then(|v| v.as_u64());
+                if saved == Some(engagement.email_count as u64) {
+                    return Ok(());
+                }
+                member
+            }
+            // Some versions reject the read-only fields outright instead
+            Err(e) if is_unprocessable(&e) => ghost::fetch_member(config, member_id).await?,
+            Err(e) => return Err(e),
+        };
+    tokio::time::sleep(UPDATE_DELAY).await;
+
+    // Replace our line in the note, leaving whatever editors wrote there alone
+    let note = member
+        .other
+        .get("note")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    let mut lines: Vec<String> = note
+        .lines()
+        .filter(|line| !line.starts_with(NOTE_PREFIX))
+        .map(|line| line.to_string())
+        .collect();
+    lines.push(engagement.note_line());
+
+    ghost::update_member(
+        config,
+        member_id,
+        serde_json::json!({ "note": lines.join("\n") }),
+    )
+    .await?;
+    Ok(())
+}
+
+fn is_unprocessable(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<ghost::ApiError>()
+        .is_some_and(|e| e.status == reqwest::StatusCode::UNPROCESSABLE_ENTITY)
+}
+
+/// Syncs engagement to Ghost in the background at the configured interval.
+pub fn spawn_sync_task(config: &Config, store: &Store) {
+    let Some(interval) = config.ghost_engagement_interval else {
+        return;
+    };
+
+    let config = config.clone();
+    let store = store.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            sync_to_ghost(&config, &store).await;
+        }
+    });
+}
@@ -1,27 +1,42 @@
+use chrono::{DateTime, Utc};
 use jsonwebtoken::{encode, EncodingKey, Header};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct WebhookPayload {
     pub post: PostWrapper,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PostWrapper {
     pub current: Post,
     pub previous: PreviousPost,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Body of Ghost's member webhooks, e.g. `member.added`.
+#[derive(Debug, Deserialize)]
+pub struct MemberWebhookPayload {
+    pub member: MemberWrapper,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MemberWrapper {
+    pub current: Member,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Post {
     pub id: String,
     pub uuid: String,
     pub title: String,
     pub slug: String,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub html: String,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub comment_id: String,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub plaintext: String,
     pub feature_image: Option<String>,
     pub featured: bool,
@@ -29,8 +44,11 @@ pub struct Post {
     pub visibility: String,
     pub created_at: String,
     pub updated_at: String,
+    /// Empty for drafts
+    #[serde(default, deserialize_with = "null_as_default")]
     pub published_at: String,
     pub url: String,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub excerpt: String,
     pub primary_author: Author,
     pub reading_time: u32,
@@ -40,7 +58,16 @@ pub struct Post {
     pub other: Value,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Drafts come back from the Admin API with `null` rather than empty strings.
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PreviousPost {
     pub status: String,
     pub updated_at: String,
@@ -49,9 +76,10 @@ pub struct PreviousPost {
     pub other: Value,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Author {
     pub name: String,
+    pub slug: Option<String>,
     pub profile_image: Option<String>,
     pub bio: Option<String>,
     pub url: String,
@@ -64,10 +92,17 @@ pub struct MembersResponse {
     pub meta: Meta,
 }
 
+/// Single member responses carry no pagination meta.
+#[derive(Debug, Deserialize)]
+struct MemberResponse {
+    members: Vec<Member>,
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 pub struct Member {
     pub id: String,
+    pub uuid: String,
     pub email: String,
     pub name: Option<String>,
     pub status: String,
@@ -77,6 +112,20 @@ pub struct Member {
     pub other: Value,
 }
 
+impl Member {
+    /// Whether the member gets emails at all, going by the `subscribed` flag and
+    /// their newsletters where Ghost includes them.
+    pub fn is_subscribed(&self) -> bool {
+        let subscribed = self.other.get("subscribed").and_then(|v| v.as_bool());
+        let newsletters = self
+            .other
+            .get("newsletters")
+            .and_then(|v| v.as_array())
+            .map(|newsletters| newsletters.len());
+        subscribed != Some(false) && newsletters != Some(0)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 pub struct Meta {
@@ -104,46 +153,79 @@ struct Claims {
 // Constants for batch processing
 const BATCH_SIZE: usize = 100; // Resend's max batch size
 
-pub async fn fetch_subscribers(config: &crate::config::Config) -> anyhow::Result<Vec<Member>> {
-    let client = reqwest::Client::new();
-    let mut all_members = Vec::new();
-    let mut current_page = 1;
+/// Creates a short-lived JWT for the Ghost Admin API from the configured key.
+fn admin_token(config: &crate::config::Config) -> anyhow::Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
-    loop {
-        let url = format!(
-            "{}/ghost/api/admin/members/?page={}&limit={}",
-            config.ghost_url,
-            current_page,
-            BATCH_SIZE // Use same batch size for consistency
-        );
+    let claims = Claims {
+        aud: "/admin/".to_string(),
+        exp: now + 300, // 5 minutes
+        iat: now,
+    };
+
+    // Create header with the ID
+    let mut header = Header::new(jsonwebtoken::Algorithm::HS256);
+    header.kid = Some(config.ghost_admin_id.clone());
+    header.typ = Some("JWT".to_string());
+
+    // Decode the hex secret into bytes
+    let secret_bytes = hex::decode(&config.ghost_admin_secret).map_err(|e| {
+        tracing::error!("Failed to decode hex secret: {}", e);
+        anyhow::anyhow!("Invalid hex secret")
+    })?;
 
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    tracing::debug!("Creating JWT with ID: {}", config.ghost_admin_id);
+
+    Ok(encode(
+        &header,
+        &claims,
+        &EncodingKey::from_secret(&secret_bytes),
+    )?)
+}
 
-        let claims = Claims {
-            aud: "/admin/".to_string(),
-            exp: now + 300, // 5 minutes
-            iat: now,
-        };
+/// Fetches every member still subscribed to emails, using Ghost's
+/// `subscribed:true` filter: members subscribed to at least one newsletter.
+/// Members who unsubscribed from everything in Ghost never get campaigns.
+pub async fn fetch_subscribers(config: &crate::config::Config) -> anyhow::Result<Vec<Member>> {
+    fetch_members(config, Some("subscribed:true")).await
+}
 
-        // Create header with the ID
-        let mut header = Header::new(jsonwebtoken::Algorithm::HS256);
-        header.kid = Some(config.ghost_admin_id.clone());
-        header.typ = Some("JWT".to_string());
+/// Fetches those of `member_ids` that still exist and are subscribed to emails.
+pub async fn fetch_subscribed_members(
+    config: &crate::config::Config,
+    member_ids: &[&str],
+) -> anyhow::Result<Vec<Member>> {
+    let filter = format!("id:[{}]+subscribed:true", member_ids.join(","));
+    fetch_members(config, Some(&filter)).await
+}
 
-        // Decode the hex secret into bytes
-        let secret_bytes = hex::decode(&config.ghost_admin_secret).map_err(|e| {
-            tracing::error!("Failed to decode hex secret: {}", e);
-            anyhow::anyhow!("Invalid hex secret")
-        })?;
+/// Fetches every member matching an optional Ghost NQL filter, e.g. `status:paid`.
+pub async fn fetch_members(
+    config: &crate::config::Config,
+    filter: Option<&str>,
+) -> anyhow::Result<Vec<Member>> {
+    let client = reqwest::Client::new();
+    let mut all_members = Vec::new();
+    let mut current_page = 1;
 
-        tracing::debug!("Creating JWT with ID: {}", config.ghost_admin_id);
+    loop {
+        let url = format!("{}/ghost/api/admin/members/", config.ghost_url);
 
-        let token = encode(&header, &claims, &EncodingKey::from_secret(&secret_bytes))?;
+        let token = admin_token(config)?;
 
         tracing::debug!("Generated token: {}", token);
 
+        let mut query = vec![
+            ("page", current_page.to_string()),
+            ("limit", BATCH_SIZE.to_string()), // Use same batch size for consistency
+        ];
+        if let Some(filter) = filter {
+            query.push(("filter", filter.to_string()));
+        }
+
         let response = client
             .get(&url)
+            .query(&query)
             .header("Authorization", format!("Ghost {}", token))
             .header("Accept-Version", "v5.0")
             .send()
@@ -213,6 +295,9 @@ pub struct Settings {
     pub description: String,
     pub accent_color: Option<String>,
     pub url: String,
+    pub members_support_address: Option<String>,
+    /// IANA name of the site's timezone, e.g. `Europe/London`
+    pub timezone: String,
 }
 
 pub async fn fetch_settings(config: &crate::config::Config) -> anyhow::Result<Settings> {
@@ -221,21 +306,7 @@ pub async fn fetch_settings(config: &crate::config::Config) -> anyhow::Result<Se
 
     tracing::debug!("Fetching settings from: {}", url);
 
-    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-
-    let claims = Claims {
-        aud: "/admin/".to_string(),
-        exp: now + 300,
-        iat: now,
-    };
-
-    // Create header with the ID
-    let mut header = Header::new(jsonwebtoken::Algorithm::HS256);
-    header.kid = Some(config.ghost_admin_id.clone());
-    header.typ = Some("JWT".to_string());
-
-    let secret_bytes = hex::decode(&config.ghost_admin_secret)?;
-    let token = encode(&header, &claims, &EncodingKey::from_secret(&secret_bytes))?;
+    let token = admin_token(config)?;
 
     let response = client
         .get(&url)
@@ -270,6 +341,8 @@ pub async fn fetch_settings(config: &crate::config::Config) -> anyhow::Result<Se
     let mut title = String::new();
     let mut description = String::new();
     let mut accent_color = None;
+    let mut members_support_address = None;
+    let mut timezone = "Etc/UTC".to_string();
 
     // Use the ghost_url from config as the url since that's what we're actually using
     let url = config.ghost_url.clone();
@@ -293,6 +366,14 @@ pub async fn fetch_settings(config: &crate::config::Config) -> anyhow::Result<Se
             "accent_color" => {
                 accent_color = setting.value.as_str().map(|s| s.to_string());
             }
+            "members_support_address" => {
+                members_support_address = setting.value.as_str().map(|s| s.to_string());
+            }
+            "timezone" => {
+                if let Some(value) = setting.value.as_str() {
+                    timezone = value.to_string();
+                }
+            }
             _ => {} // Ignore other settings
         }
     }
@@ -302,5 +383,256 @@ pub async fn fetch_settings(config: &crate::config::Config) -> anyhow::Result<Se
         description,
         accent_color,
         url,
+        members_support_address,
+        timezone,
     })
 }
+
+/// Looks up a single member by their public `uuid`.
+pub async fn fetch_member_by_uuid(
+    config: &crate::config::Config,
+    uuid: &str,
+) -> anyhow::Result<Option<Member>> {
+    let client = reqwest::Client::new();
+    let url = format!("{}/ghost/api/admin/members/", config.ghost_url);
+    let token = admin_token(config)?;
+
+    let response = client
+        .get(&url)
+        .query(&[
+            ("filter", format!("uuid:'{}'", uuid)),
+            ("limit", "1".into()),
+        ])
+        .header("Authorization", format!("Ghost {}", token))
+        .header("Accept-Version", "v5.0")
+        .send()
+        .await?;
+
+    let status = response.status();
+    let body = response.text().await?;
+
+    if !status.is_success() {
+        tracing::error!("Ghost API error response: {}", body);
+        return Err(anyhow::anyhow!("Ghost API returned error: {}", status));
+    }
+
+    let response: MembersResponse = serde_json::from_str(&body)?;
+    Ok(response.members.into_iter().next())
+}
+
+/// Fetches a post with its HTML and authors.
+pub async fn fetch_post(config: &crate::config::Config, post_id: &str) -> anyhow::Result<Post> {
+    let client = reqwest::Client::new();
+    let url = format!("{}/ghost/api/admin/posts/{}/", config.ghost_url, post_id);
+    let token = admin_token(config)?;
+
+    let response = client
+        .get(&url)
+        .query(&[("formats", "html,plaintext"), ("include", "authors,tags")])
+        .header("Authorization", format!("Ghost {}", token))
+        .header("Accept-Version", "v5.0")
+        .send()
+        .await?;
+
+    let status = response.status();
+    let body = response.text().await?;
+
+    if !status.is_success() {
+        tracing::error!("Ghost API error response: {}", body);
+        return Err(anyhow::anyhow!("Ghost API returned error: {}", status));
+    }
+
+    #[derive(Deserialize)]
+    struct PostsResponse {
+        posts: Vec<Post>,
+    }
+
+    let response: PostsResponse = serde_json::from_str(&body)?;
+    response
+        .posts
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Post {} not found", post_id))
+}
+
+/// Fetches the posts published after `from` and up to `to`, oldest first.
+pub async fn fetch_published_posts(
+    config: &crate::config::Config,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> anyhow::Result<Vec<Post>> {
+    let client = reqwest::Client::new();
+    let url = format!("{}/ghost/api/admin/posts/", config.ghost_url);
+    let token = admin_token(config)?;
+
+    let filter = format!(
+        "status:published+published_at:>'{}'+published_at:<='{}'",
+        from.format("%Y-%m-%d %H:%M:%S"),
+        to.format("%Y-%m-%d %H:%M:%S")
+    );
+
+    let response = client
+        .get(&url)
+        .query(&[
+            ("filter", filter.as_str()),
+            ("formats", "html,plaintext"),
+            ("include", "authors,tags"),
+            ("order", "published_at asc"),
+            ("limit", "all"),
+        ])
+        .header("Authorization", format!("Ghost {}", token))
+        .header("Accept-Version", "v5.0")
+        .send()
+        .await?;
+
+    let status = response.status();
+    let body = response.text().await?;
+
+    if !status.is_success() {
+        tracing::error!("Ghost API error response: {}", body);
+        return Err(anyhow::anyhow!("Ghost API returned error: {}", status));
+    }
+
+    #[derive(Deserialize)]
+    struct PostsResponse {
+        posts: Vec<Post>,
+    }
+
+    let response: PostsResponse = serde_json::from_str(&body)?;
+    Ok(response.posts)
+}
+
+/// Applies a partial update to a member, returning the member as Ghost saved it.
+pub async fn update_member(
+    config: &crate::config::Config,
+    member_id: &str,
+    changes: Value,
+) -> anyhow::Result<Member> {
+    let client = reqwest::Client::new();
+    let url = format!(
+        "{}/ghost/api/admin/members/{}/",
+        config.ghost_url, member_id
+    );
+    let token = admin_token(config)?;
+
+    let response = client
+        .put(&url)
+        .header("Authorization", format!("Ghost {}", token))
+        .header("Accept-Version", "v5.0")
+        .json(&serde_json::json!({ "members": [changes] }))
+        .send()
+        .await?;
+
+    let status = response.status();
+    let body = response.text().await?;
+
+    if !status.is_success() {
+        tracing::error!("Ghost API error response: {}", body);
+        return Err(ApiError { status }.into());
+    }
+
+    let response: MemberResponse = serde_json::from_str(&body)?;
+    response
+        .members
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Member {} not found", member_id))
+}
+
+/// Ghost answered a request with an error status, for callers that handle some
+/// statuses differently.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: reqwest::StatusCode,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Ghost API returned error: {}", self.status)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Fetches a single member by ID.
+pub async fn fetch_member(
+    config: &crate::config::Config,
+    member_id: &str,
+) -> anyhow::Result<Member> {
+    let client = reqwest::Client::new();
+    let url = format!(
+        "{}/ghost/api/admin/members/{}/",
+        config.ghost_url, member_id
+    );
+    let token = admin_token(config)?;
+
+    let response = client
+        .get(&url)
+        .header("Authorization", format!("Ghost {}", token))
+        .header("Accept-Version", "v5.0")
+        .send()
+        .await?;
+
+    let status = response.status();
+    let body = response.text().await?;
+
+    if !status.is_success() {
+        tracing::error!("Ghost API error response: {}", body);
+        return Err(anyhow::anyhow!("Ghost API returned error: {}", status));
+    }
+
+    let response: MemberResponse = serde_json::from_str(&body)?;
+    response
+        .members
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Member {} not found", member_id))
+}
+
+/// Unsubscribes a member from every newsletter.
+pub async fn unsubscribe_member(
+    config: &crate::config::Config,
+    member_id: &str,
+) -> anyhow::Result<()> {
+    update_member(config, member_id, serde_json::json!({ "newsletters": [] })).await?;
+
+    tracing::debug!("Unsubscribed member {} from all newsletters", member_id);
+    Ok(())
+}
+
+/// Adds a label to a member, keeping the labels they already have.
+pub async fn add_member_label(
+    config: &crate::config::Config,
+    member_id: &str,
+    label: &str,
+) -> anyhow::Result<()> {
+    let member = fetch_member(config, member_id).await?;
+
+    // Ghost replaces the whole label set on update, so resend the existing ones
+    let mut labels: Vec<String> = member
+        .other
+        .get("labels")
+        .and_then(|l| l.as_array())
+        .map(|labels| {
+            labels
+                .iter()
+                .filter_map(|l| l.get("name").and_then(|n| n.as_str()))
+                .map(|n| n.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if labels.iter().any(|l| l == label) {
+        return Ok(());
+    }
+    labels.push(label.to_string());
+
+    let labels: Vec<Value> = labels
+        .into_iter()
+        .map(|name| serde_json::json!({ "name": name }))
+        .collect();
+    update_member(config, member_id, serde_json::json!({ "labels": labels })).await?;
+
+    tracing::debug!("Added label {} to member {}", label, member_id);
+    Ok(())
+}
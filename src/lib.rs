@@ -0,0 +1,21 @@
+pub mod admin;
+pub mod analytics;
+pub mod cli;
+pub mod config;
+pub mod digest;
+pub mod email;
+pub mod engagement;
+pub mod ghost;
+pub mod mailer;
+pub mod page;
+pub mod preview;
+pub mod queue;
+pub mod resend;
+pub mod schedule;
+pub mod state;
+pub mod store;
+pub mod suppression;
+pub mod tracking;
+pub mod unsubscribe;
+pub mod webhook;
+pub mod welcome;
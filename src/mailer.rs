@@ -0,0 +1,150 @@
+use crate::{
+    config::Config,
+    store::{RecipientStatus, Store},
+};
+use resend_rs::{types::CreateEmailBaseOptions, Resend};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Where emails go: to Resend, or nowhere for dry runs.
+pub enum Mailer {
+    Resend(Resend),
+    DryRun,
+}
+
+impl Mailer {
+    /// Resend, unless `DRY_RUN` is on.
+    pub fn new(config: &Config) -> Self {
+        Mailer::for_run(config, config.dry_run)
+    }
+
+    pub fn for_run(config: &Config, dry_run: bool) -> Self {
+        if dry_run {
+            Mailer::DryRun
+        } else {
+            Mailer::Resend(Resend::new(&config.resend_api_key))
+        }
+    }
+
+    /// Sends a batch of emails and returns their IDs in the same order. Dry runs
+    /// make up IDs starting with `dry-run-`.
+    pub async fn send_batch(
+        &self,
+        emails: Vec<CreateEmailBaseOptions>,
+    ) -> anyhow::Result<Vec<String>> {
+        match self {
+            Mailer::Resend(client) => Ok(client
+                .batch
+                .send(emails)
+                .await?
+                .iter()
+                .map(|response| response.id.to_string())
+                .collect()),
+            Mailer::DryRun => {
+                tracing::info!("Dry run, not sending {} emails", emails.len());
+                Ok(emails
+                    .iter()
+                    .map(|_| format!("dry-run-{}", uuid::Uuid::new_v4().simple()))
+                    .collect())
+            }
+        }
+    }
+}
+
+/// An email a dry run would have sent, as it would have been handed to Resend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DryRunEmail {
+    pub email_id: String,
+    /// Batch the email would have gone out in, counting from 1
+    pub batch: usize,
+    pub to: Vec<String>,
+    pub from: String,
+    pub subject: String,
+    #[serde(default)]
+    pub reply_to: Vec<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Size of the rendered HTML, the content itself is what the preview shows
+    pub html_bytes: usize,
+}
+
+impl DryRunEmail {
+    pub fn new(email: &CreateEmailBaseOptions, batch: usize) -> anyhow::Result<Self> {
+        // Resend's options keep their fields private, their JSON form doesn't
+        #[derive(Deserialize)]
+        struct Options {
+            to: Vec<String>,
+            from: String,
+            subject: String,
+            #[serde(default)]
+            reply_to: Vec<String>,
+            #[serde(default)]
+            headers: HashMap<String, String>,
+            #[serde(default)]
+            html: String,
+        }
+
+        let options: Options = serde_json::from_value(serde_json::to_value(email)?)?;
+        Ok(DryRunEmail {
+            email_id: String::new(),
+            batch,
+            to: options.to,
+            from: options.from,
+            subject: options.subject,
+            reply_to: options.reply_to,
+            headers: options.headers,
+            html_bytes: options.html.len(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedRecipient {
+    pub email: String,
+    pub reason: String,
+}
+
+/// Who a dry run campaign would have sent what, and in how many batches.
+#[derive(Debug, Serialize)]
+pub struct DryRunReport {
+    pub campaign_id: String,
+    pub post_id: String,
+    pub post_title: String,
+    pub emails: usize,
+    pub batches: usize,
+    /// Recipients the queue hasn't gotten to yet
+    pub pending: usize,
+    pub skipped: Vec<SkippedRecipient>,
+    pub sent: Vec<DryRunEmail>,
+}
+
+/// The report of a dry run campaign, `None` if the campaign doesn't exist or
+/// was sent for real.
+pub fn dry_run_report(store: &Store, campaign_id: &str) -> Option<DryRunReport> {
+    let campaign = store.campaign(campaign_id).filter(|c| c.dry_run)?;
+    let recipients = store.recipients(campaign_id);
+    let sent = store.dry_run_emails(campaign_id);
+
+    Some(DryRunReport {
+        campaign_id: campaign.id,
+        post_id: campaign.post_id,
+        post_title: campaign.post_title,
+        emails: sent.len(),
+        batches: sent.iter().map(|email| email.batch).max().unwrap_or(0),
+        pending: recipients
+            .iter()
+            .filter(|recipient| recipient.status == RecipientStatus::Pending)
+            .count(),
+        skipped: recipients
+            .into_iter()
+            .filter_map(|recipient| match recipient.status {
+                RecipientStatus::Skipped { reason } => Some(SkippedRecipient {
+                    email: recipient.email,
+                    reason,
+                }),
+                _ => None,
+            })
+            .collect(),
+        sent,
+    })
+}
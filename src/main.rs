@@ -1,17 +1,126 @@
-mod config;
-mod email;
-mod ghost;
-mod webhook;
-
 use axum::{
     http::StatusCode,
     routing::{get, post},
     Router,
 };
+use clap::{Parser, Subcommand};
+use ghost_resend_mailer::{
+    admin, cli, config::Config, digest, engagement, preview, queue::Queue, resend, state::AppState,
+    tracking, unsubscribe, webhook, welcome,
+};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use tokio::signal;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+#[derive(Parser)]
+#[command(version, about = "Sends Ghost posts to members through Resend")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server (the default)
+    Serve,
+    /// Send a post to every subscriber and wait until it's sent
+    Send {
+        /// Ghost post ID
+        #[arg(long)]
+        post: String,
+        /// Send even if the post was sent before
+        #[arg(long)]
+        force: bool,
+        /// Go through every step without sending anything and print what would be sent
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Render a post as members receive it
+    Preview {
+        /// Ghost post ID, drafts work too
+        #[arg(long)]
+        post: String,
+        /// Render for this Ghost member instead of a sample one
+        #[arg(long)]
+        member: Option<String>,
+        /// Render the plain text version
+        #[arg(long)]
+        text: bool,
+        /// File to write to instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Send a post, drafts included, to a few addresses with a [TEST] subject prefix
+    TestSend {
+        /// Ghost post ID
+        #[arg(long)]
+        post: String,
+        /// Addresses to send the test to
+        #[arg(required = true)]
+        emails: Vec<String>,
+    },
+    /// Look up Ghost members
+    Members {
+        #[command(subcommand)]
+        command: MembersCommand,
+    },
+    /// Inspect and resume campaigns
+    Campaigns {
+        #[command(subcommand)]
+        command: CampaignsCommand,
+    },
+    /// Send or preview the digest of recent posts
+    Digest {
+        #[command(subcommand)]
+        command: DigestCommand,
+    },
+    /// Check the Ghost and Resend credentials and show enabled features
+    CheckConfig,
+}
+
+#[derive(Subcommand)]
+enum MembersCommand {
+    /// List members as tab separated ID, email, name and status
+    List {
+        /// Ghost NQL filter, e.g. `status:paid` or `label:vip`
+        #[arg(long)]
+        filter: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CampaignsCommand {
+    /// List campaigns, newest first
+    List,
+    /// Show a campaign's progress and stats
+    Show {
+        /// Campaign ID
+        id: String,
+    },
+    /// Unpause the queue and send what's left, optionally restarting a cancelled campaign
+    Resume {
+        /// Campaign ID
+        id: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum DigestCommand {
+    /// Send the digest now instead of waiting for its schedule
+    Send {
+        /// Go through every step without sending anything and print what would be sent
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Render the next digest as members would receive it
+    Preview {
+        /// File to write to instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
 async fn health_check() -> StatusCode {
     StatusCode::OK
 }
@@ -44,6 +153,8 @@ async fn shutdown_signal() {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
     // Initialize tracing
     tracing_subscriber::registry()
         .with(
@@ -53,21 +164,92 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     // Load configuration
-    let config = config::Config::from_env()?;
+    let config = Config::from_env()?;
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config).await,
+        Command::Send {
+            post,
+            force,
+            dry_run,
+        } => cli::send(&config, &post, force, dry_run).await,
+        Command::Preview {
+            post,
+            member,
+            text,
+            out,
+        } => cli::preview(&config, &post, member.as_deref(), text, out.as_deref()).await,
+        Command::TestSend { post, emails } => {
+            for email_id in preview::send_test(&config, &post, &emails).await? {
+                println!("{}", email_id);
+            }
+            Ok(())
+        }
+        Command::Members {
+            command: MembersCommand::List { filter },
+        } => cli::list_members(&config, filter.as_deref()).await,
+        Command::Campaigns { command } => match command {
+            CampaignsCommand::List => cli::list_campaigns(&config),
+            CampaignsCommand::Show { id } => cli::show_campaign(&config, &id),
+            CampaignsCommand::Resume { id } => cli::resume_campaigns(&config, id.as_deref()).await,
+        },
+        Command::Digest { command } => match command {
+            DigestCommand::Send { dry_run } => cli::send_digest(&config, dry_run).await,
+            DigestCommand::Preview { out } => cli::preview_digest(&config, out.as_deref()).await,
+        },
+        Command::CheckConfig => cli::check_config(&config).await,
+    }
+}
+
+async fn serve(config: Config) -> anyhow::Result<()> {
     let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
 
+    if config.dry_run {
+        tracing::warn!("DRY_RUN is on, campaigns won't send any emails");
+    }
+
+    // Load campaign and delivery state
+    let store = cli::claim_store(&config)?;
+    engagement::spawn_sync_task(&config, &store);
+
+    // Campaigns are sent in the background, picking up where they left off
+    let queue = Queue::new(config.clone(), store.clone());
+    queue.spawn();
+    digest::spawn_task(&config, &store, &queue);
+
+    let state = AppState {
+        config,
+        store: store.clone(),
+        queue,
+    };
+
     // Build application with health check
     let app = Router::new()
         .route("/webhook", post(webhook::handle_webhook))
+        .route("/webhook/member", post(welcome::handle_member_webhook))
+        .route("/resend/webhook", post(resend::handle_resend_webhook))
+        .route(
+            "/unsubscribe",
+            get(unsubscribe::unsubscribe_page).post(unsubscribe::handle_unsubscribe),
+        )
+        .route("/track/open/:token", get(tracking::handle_open))
+        .route("/c/r/:token", get(tracking::handle_click))
         .route("/health", get(health_check))
-        .with_state(config);
+        .nest("/admin", admin::router(state.clone()))
+        .with_state(state);
 
     // Start server
     tracing::info!("listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
+
+    // Persist anything recorded since the last periodic flush
+    cli::release_store(&store)?;
 
     Ok(())
 }
@@ -0,0 +1,25 @@
+use crate::email::escape_html;
+
+/// A standalone HTML page served by this service, like the unsubscribe
+/// confirmation or a campaign report. `style` is CSS added to the shared base.
+pub fn page(title: &str, style: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <title>{title}</title>
+    <style>
+        body {{ font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif; margin: 40px auto; padding: 0 20px; color: #333; }}
+        {style}
+    </style>
+</head>
+<body>
+    <h1>{title}</h1>
+    {body}
+</body>
+</html>"#,
+        title = escape_html(title)
+    )
+}
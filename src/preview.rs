@@ -0,0 +1,168 @@
+use crate::{
+    config::Config,
+    email,
+    ghost::{self, Member},
+    mailer::Mailer,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use resend_rs::types::CreateEmailBaseOptions;
+use serde::{Deserialize, Serialize};
+
+const TEST_SUBJECT_PREFIX: &str = "[TEST] ";
+
+// Test sends go out in a single Resend batch
+const MAX_TEST_RECIPIENTS: usize = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewParams {
+    /// Render for this Ghost member instead of a sample one
+    pub member_id: Option<String>,
+    /// `html` (default) or `text`
+    pub format: Option<String>,
+}
+
+/// Stand-in recipient for previews, so merge tags show realistic values.
+pub fn sample_member() -> Member {
+    Member {
+        id: "preview".to_string(),
+        uuid: "00000000-0000-0000-0000-000000000000".to_string(),
+        email: "jamie@example.com".to_string(),
+        name: Some("Jamie Larson".to_string()),
+        status: "free".to_string(),
+        created_at: String::new(),
+        updated_at: String::new(),
+        other: serde_json::Value::Null,
+    }
+}
+
+/// Renders a post, including drafts, exactly as a member would receive it.
+pub async fn preview_post(
+    State(config): State<Config>,
+    Path(post_id): Path<String>,
+    Query(params): Query<PreviewParams>,
+) -> Result<Response, StatusCode> {
+    let post = ghost::fetch_post(&config, &post_id).await.map_err(|e| {
+        tracing::error!("Failed to fetch post {}: {}", post_id, e);
+        StatusCode::NOT_FOUND
+    })?;
+
+    let settings = ghost::fetch_settings(&config).await.map_err(|e| {
+        tracing::error!("Failed to fetch Ghost settings: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let member = match &params.member_id {
+        Some(member_id) => ghost::fetch_member(&config, member_id).await.map_err(|e| {
+            tracing::error!("Failed to fetch member {}: {}", member_id, e);
+            StatusCode::NOT_FOUND
+        })?,
+        None => sample_member(),
+    };
+
+    let html = email::format_email(&post, &member, &config, &settings).map_err(|e| {
+        tracing::error!("Failed to format email: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    match params.format.as_deref() {
+        None | Some("html") => Ok(Html(html).into_response()),
+        Some("text") => {
+            let text = email::html_to_text(&html).map_err(|e| {
+                tracing::error!("Failed to convert email to text: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response())
+        }
+        Some(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// Sends a post, drafts included, to a few addresses for review and returns the
+/// Resend email IDs. Test sends create no campaign, so they never show up in
+/// stats or stop the post from being sent for real.
+pub async fn send_test(
+    config: &Config,
+    post_id: &str,
+    addresses: &[String],
+) -> anyhow::Result<Vec<String>> {
+    check_test_recipients(addresses)?;
+
+    let post = ghost::fetch_post(config, post_id).await?;
+    let settings = ghost::fetch_settings(config).await?;
+
+    let compiled = email::compile_email(&post, config, &settings)?;
+    let from = email::from_address(&post, config, &settings);
+    let reply_to = email::reply_to(config, &settings);
+
+    let batch: Vec<CreateEmailBaseOptions> = addresses
+        .iter()
+        .map(|address| {
+            let member = Member {
+                email: address.clone(),
+                ..sample_member()
+            };
+            let personalized = compiled.personalize(&member, config);
+
+            let mut email = CreateEmailBaseOptions::new(
+                &from,
+                vec![address.clone()],
+                format!("{}{}", TEST_SUBJECT_PREFIX, personalized.subject),
+            )
+            .with_html(&personalized.html);
+            if let Some(reply_to) = &reply_to {
+                email = email.with_reply(reply_to);
+            }
+            email
+        })
+        .collect();
+
+    let email_ids = Mailer::new(config).send_batch(batch).await?;
+
+    tracing::info!("Sent test of post {} to {}", post_id, addresses.join(", "));
+    Ok(email_ids)
+}
+
+fn check_test_recipients(addresses: &[String]) -> anyhow::Result<()> {
+    if addresses.is_empty() || addresses.len() > MAX_TEST_RECIPIENTS {
+        return Err(anyhow::anyhow!(
+            "Test sends need between 1 and {} addresses",
+            MAX_TEST_RECIPIENTS
+        ));
+    }
+    if let Some(invalid) = addresses.iter().find(|address| !address.contains('@')) {
+        return Err(anyhow::anyhow!("{} is not an email address", invalid));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TestSendRequest {
+    pub emails: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TestSendResponse {
+    pub email_ids: Vec<String>,
+}
+
+pub async fn handle_test_send(
+    State(config): State<Config>,
+    Path(post_id): Path<String>,
+    Json(request): Json<TestSendRequest>,
+) -> Result<Json<TestSendResponse>, (StatusCode, String)> {
+    check_test_recipients(&request.emails).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let email_ids = send_test(&config, &post_id, &request.emails)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to send test of post {}: {}", post_id, e);
+            (StatusCode::BAD_GATEWAY, e.to_string())
+        })?;
+
+    Ok(Json(TestSendResponse { email_ids }))
+}
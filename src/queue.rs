@@ -0,0 +1,700 @@
+use crate::{
+    config::{Config, DEFAULT_DIGEST_SUBJECT},
+    digest, email, ghost,
+    ghost::{Member, Post},
+    mailer::{DryRunEmail, Mailer},
+    schedule::{self, MemberTimezone},
+    store::{Campaign, CampaignStatus, Recipient, RecipientStatus, SendRecord, Store},
+    unsubscribe,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use resend_rs::types::CreateEmailBaseOptions;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+// Constants for batch processing
+const BATCH_SIZE: usize = 100; // Resend's max batch size
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+
+// How often the worker looks for work when nothing wakes it up
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// Wait before retrying a campaign that couldn't be prepared, e.g. while Ghost is
+// down, multiplied by the failures so far
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// A campaign failing this many times in a row is cancelled so it stops holding up
+// the campaigns after it
+const MAX_CAMPAIGN_FAILURES: u32 = 5;
+
+/// How a campaign is sent.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendOptions {
+    /// Go through every step without handing anything to Resend
+    pub dry_run: bool,
+    /// Hold the campaign until then, instead of when the post's send time tag
+    /// or the configured defaults say
+    pub send_at: Option<DateTime<Utc>>,
+    /// Send a post again even if it already has a campaign
+    pub force: bool,
+}
+
+/// Sends queued campaigns in the background, one batch at a time, so webhooks
+/// return right away and campaigns survive restarts.
+#[derive(Clone)]
+pub struct Queue {
+    config: Config,
+    store: Store,
+    wake: Arc<Notify>,
+}
+
+impl Queue {
+    pub fn new(config: Config, store: Store) -> Self {
+        Queue {
+            config,
+            store,
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Starts the worker that sends queued campaigns.
+    pub fn spawn(&self) {
+        let queue = self.clone();
+        tokio::spawn(async move { queue.run().await });
+    }
+
+    /// Tells the worker to check for work now rather than at its next poll.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    pub fn pause(&self) {
+        self.store.set_queue_paused(true);
+        tracing::info!("Queue paused");
+    }
+
+    pub fn resume(&self) {
+        self.store.set_queue_paused(false);
+        tracing::info!("Queue resumed");
+        self.wake();
+    }
+
+    /// Creates a campaign sending `post` to every subscriber and queues it.
+    /// Suppressed subscribers and those who get the digest instead are recorded
+    /// as skipped. Dry runs go through every step but hand nothing to Resend.
+    ///
+    /// Unless forced, a post that already has a campaign isn't queued again and
+    /// the existing campaign is returned instead.
+    pub async fn enqueue_post(&self, post: Post, options: SendOptions) -> anyhow::Result<Campaign> {
+        let subscribers = ghost::fetch_subscribers(&self.config).await?;
+
+        let settings = ghost::fetch_settings(&self.config).await?;
+        let timezone = schedule::site_timezone(&settings.timezone);
+
+        let now = Utc::now();
+        let send_at = options
+            .send_at
+            .or_else(|| schedule::send_at(&post, &self.config, timezone, now));
+
+        let mut recipients = self.recipients(&subscribers, send_at, timezone, now)?;
+        if let Some(digest) = &self.config.digest {
+            for (recipient, subscriber) in recipients.iter_mut().zip(&subscribers) {
+                if recipient.status == RecipientStatus::Pending
+                    && digest::is_opted_in(&digest.audience, subscriber)
+                {
+                    recipient.status = RecipientStatus::Skipped {
+                        reason: "digest".to_string(),
+                    };
+                }
+            }
+        }
+
+        let campaign = Campaign {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            post_id: post.id.clone(),
+            post_title: post.title.clone(),
+            created_at: now,
+            status: CampaignStatus::Queued,
+            post: Some(post),
+            dry_run: options.dry_run,
+            send_at,
+            digest: None,
+            welcome: false,
+            failures: 0,
+            retry_at: None,
+            finished_at: None,
+        };
+
+        let recipient_count = recipients.len();
+        if options.force {
+            self.store.insert_campaign(campaign.clone(), recipients);
+        } else if let Err(existing) = self
+            .store
+            .insert_post_campaign(campaign.clone(), recipients)
+        {
+            tracing::info!(
+                "Post {} was already queued as campaign {}, not queueing it again",
+                existing.post_id,
+                existing.id
+            );
+            return Ok(existing);
+        }
+
+        tracing::info!(
+            "Queued {}campaign {} for post {} to {} subscribers{}",
+            if campaign.dry_run { "dry run " } else { "" },
+            campaign.id,
+            campaign.post_id,
+            recipient_count,
+            match send_at {
+                Some(send_at) => format!(", sending at {}", send_at),
+                None => String::new(),
+            }
+        );
+        self.wake();
+
+        Ok(campaign)
+    }
+
+    /// Creates a campaign sending a digest of `posts` to the members opted into
+    /// it and queues it.
+    pub async fn enqueue_digest(
+        &self,
+        posts: Vec<Post>,
+        options: SendOptions,
+    ) -> anyhow::Result<Campaign> {
+        let digest = self
+            .config
+            .digest
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Digests are not configured"))?;
+        let filter = digest::member_filter(&digest.audience);
+        let members = ghost::fetch_members(&self.config, Some(&filter)).await?;
+
+        let settings = ghost::fetch_settings(&self.config).await?;
+        let timezone = schedule::site_timezone(&settings.timezone);
+
+        let now = Utc::now();
+        let recipients = self.recipients(&members, options.send_at, timezone, now)?;
+
+        let campaign = Campaign {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            // Digests have no post of their own to be looked up by
+            post_id: "digest".to_string(),
+            post_title: format!("Digest of {} posts", posts.len()),
+            created_at: now,
+            status: CampaignStatus::Queued,
+            post: None,
+            dry_run: options.dry_run,
+            send_at: options.send_at,
+            digest: Some(posts),
+            welcome: false,
+            failures: 0,
+            retry_at: None,
+            finished_at: None,
+        };
+
+        tracing::info!(
+            "Queued {}digest campaign {} to {} members",
+            if campaign.dry_run { "dry run " } else { "" },
+            campaign.id,
+            recipients.len()
+        );
+        self.store.insert_campaign(campaign.clone(), recipients);
+        self.wake();
+
+        Ok(campaign)
+    }
+
+    /// Adds a new member to every step of the welcome sequence, each held until
+    /// its delay after now. A step's campaign is created for its first member
+    /// and picks up edits to the post with every member after that.
+    pub async fn enqueue_welcome(&self, member: &Member) -> anyhow::Result<()> {
+        let now = Utc::now();
+        let suppressed = self
+            .store
+            .suppression_matcher()?
+            .is_suppressed(&member.email);
+
+        for step in &self.config.welcome_emails {
+            let post = ghost::fetch_post(&self.config, &step.post_id).await?;
+
+            let campaign_id = match self
+                .store
+                .welcome_campaign(&step.post_id, self.config.dry_run)
+            {
+                Some(campaign) => {
+                    self.store.set_campaign_post(&campaign.id, post);
+                    campaign.id
+                }
+                None => {
+                    let campaign = Campaign {
+                        id: uuid::Uuid::new_v4().simple().to_string(),
+                        post_id: step.post_id.clone(),
+                        post_title: post.title.clone(),
+                        created_at: now,
+                        status: CampaignStatus::Queued,
+                        post: Some(post),
+                        dry_run: self.config.dry_run,
+                        send_at: None,
+                        digest: None,
+                        welcome: true,
+                        failures: 0,
+                        retry_at: None,
+                        finished_at: None,
+                    };
+                    tracing::info!(
+                        "Created welcome campaign {} for post {}",
+                        campaign.id,
+                        campaign.post_id
+                    );
+                    let campaign_id = campaign.id.clone();
+                    self.store.insert_campaign(campaign, Vec::new());
+                    campaign_id
+                }
+            };
+
+            let status = if suppressed {
+                RecipientStatus::Skipped {
+                    reason: "suppressed".to_string(),
+                }
+            } else {
+                RecipientStatus::Pending
+            };
+            let mut recipient = Recipient::new(member, status);
+            recipient.send_at = Some(now + TimeDelta::from_std(step.delay)?);
+
+            // Ghost retries webhooks, a member only starts the sequence once
+            if !self.store.add_recipient(&campaign_id, recipient) {
+                tracing::info!(
+                    "Member {} is already in welcome campaign {}",
+                    member.id,
+                    campaign_id
+                );
+            }
+        }
+
+        tracing::info!("Queued welcome emails for member {}", member.id);
+        self.wake();
+        Ok(())
+    }
+
+    /// Recipients for a campaign to `members`, skipping suppressed addresses.
+    /// With local delivery on, each is held until their own time of day.
+    fn recipients(
+        &self,
+        members: &[Member],
+        send_at: Option<DateTime<Utc>>,
+        timezone: Tz,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<Recipient>> {
+        // Each recipient gets their own time, within a day of the campaign's
+        let local_delivery = self
+            .config
+            .local_delivery_time
+            .map(|time| (time, self.store.engagement_times()));
+
+        // Never mail addresses that bounced or complained before
+        let suppressions = self.store.suppression_matcher()?;
+        Ok(members
+            .iter()
+            .map(|member| {
+                let status = if suppressions.is_suppressed(&member.email) {
+                    RecipientStatus::Skipped {
+                        reason: "suppressed".to_string(),
+                    }
+                } else {
+                    RecipientStatus::Pending
+                };
+                let mut recipient = Recipient::new(member, status);
+                if let Some((time, engagement)) = &local_delivery {
+                    let engagement = engagement
+                        .get(&member.id)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default();
+                    let base = send_at.unwrap_or(now);
+                    recipient.send_at = Some(match MemberTimezone::of(member, engagement) {
+                        Some(member_timezone) => member_timezone.next_local_time(*time, base),
+                        None => schedule::next_local_time(*time, &timezone, base),
+                    });
+                }
+                recipient
+            })
+            .collect())
+    }
+
+    async fn run(&self) {
+        loop {
+            let next = if self.store.queue_paused() {
+                None
+            } else {
+                self.store.next_active_campaign()
+            };
+
+            let Some(campaign) = next else {
+                // Wake up in time for the next campaign held for later
+                let wait = self
+                    .store
+                    .next_send_at()
+                    .and_then(|send_at| (send_at - Utc::now()).to_std().ok())
+                    .map_or(POLL_INTERVAL, |until| until.min(POLL_INTERVAL));
+                let _ = tokio::time::timeout(wait, self.wake.notified()).await;
+                continue;
+            };
+
+            match self.send_campaign(&campaign).await {
+                Ok(()) if campaign.failures > 0 => self.store.clear_campaign_failures(&campaign.id),
+                Ok(()) => {}
+                Err(e) => self.record_failure(&campaign, e),
+            }
+        }
+    }
+
+    /// Holds a campaign that failed to send for a while, so the campaigns after
+    /// it go ahead, and cancels it once it has failed too often.
+    fn record_failure(&self, campaign: &Campaign, error: anyhow::Error) {
+        let delay = RETRY_DELAY * (campaign.failures + 1);
+        let retry_at = Utc::now() + TimeDelta::from_std(delay).unwrap_or_default();
+        let failures = self.store.record_campaign_failure(&campaign.id, retry_at);
+
+        if failures >= MAX_CAMPAIGN_FAILURES {
+            tracing::error!(
+                "Failed to send campaign {} {} times, cancelling it: {}",
+                campaign.id,
+                failures,
+                error
+            );
+            self.store
+                .set_campaign_status(&campaign.id, CampaignStatus::Cancelled);
+        } else {
+            tracing::error!(
+                "Failed to send campaign {}, retrying in {} seconds: {}",
+                campaign.id,
+                delay.as_secs(),
+                error
+            );
+        }
+    }
+
+    /// Sends every active campaign and returns once none are left, for sending
+    /// from the command line without the background worker.
+    pub async fn run_all_until_idle(&self) -> anyhow::Result<()> {
+        while !self.store.queue_paused() {
+            let Some(campaign) = self.store.next_active_campaign() else {
+                break;
+            };
+            self.send_campaign(&campaign).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends one campaign and returns once nothing more of it can be sent now,
+    /// leaving the rest of the queue alone.
+    pub async fn run_until_idle(&self, campaign_id: &str) -> anyhow::Result<()> {
+        while !self.store.queue_paused() {
+            let Some(campaign) = self.store.ready_campaign(campaign_id) else {
+                break;
+            };
+            self.send_campaign(&campaign).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the pending recipients of a campaign until it is done, cancelled or
+    /// the queue is paused.
+    async fn send_campaign(&self, campaign: &Campaign) -> anyhow::Result<()> {
+        let config = &self.config;
+        let settings = ghost::fetch_settings(config).await?;
+
+        // Finished campaigns lose their post after a while, retrying one renders
+        // the post as it is now
+        let fetched;
+        let post = match &campaign.post {
+            None if campaign.digest.is_none() && campaign.post_id != "digest" => {
+                fetched = ghost::fetch_post(config, &campaign.post_id).await?;
+                self.store.set_campaign_post(&campaign.id, fetched.clone());
+                Some(&fetched)
+            }
+            post => post.as_ref(),
+        };
+
+        // Render the email once, each recipient only fills in their own details
+        let (compiled, list_id, from) = match (post, &campaign.digest) {
+            (Some(post), _) => (
+                email::compile_email(post, config, &settings)?,
+                email::list_id(post, &settings),
+                email::from_address(post, config, &settings),
+            ),
+            (None, Some(posts)) => {
+                let subject = config
+                    .digest
+                    .as_ref()
+                    .map_or(DEFAULT_DIGEST_SUBJECT, |digest| &digest.subject_template);
+                (
+                    email::compile_digest(posts, subject, config, &settings)?,
+                    email::digest_list_id(&settings),
+                    email::site_from_address(config, &settings),
+                )
+            }
+            (None, None) => {
+                tracing::warn!("Campaign {} has no post to send, cancelling", campaign.id);
+                self.store
+                    .set_campaign_status(&campaign.id, CampaignStatus::Cancelled);
+                return Ok(());
+            }
+        };
+        let compiled = compiled.for_campaign(&campaign.id);
+        let reply_to = email::reply_to(config, &settings);
+
+        self.store
+            .set_campaign_status(&campaign.id, CampaignStatus::Sending);
+        tracing::info!("Sending campaign {}", campaign.id);
+
+        let mailer = Mailer::for_run(config, campaign.dry_run);
+        let mut batch_index = 0;
+
+        loop {
+            // Pausing and cancelling take effect between batches
+            if self.store.queue_paused() {
+                tracing::info!("Queue paused, stopping campaign {}", campaign.id);
+                return Ok(());
+            }
+            let status = self.store.campaign(&campaign.id).map(|c| c.status);
+            if status != Some(CampaignStatus::Sending) {
+                tracing::info!("Campaign {} was cancelled", campaign.id);
+                return Ok(());
+            }
+
+            let batch = self.store.due_recipients(&campaign.id, BATCH_SIZE);
+
+            // Addresses can be suppressed while a campaign waits for its send
+            // time, e.g. after bouncing on an earlier campaign
+            let suppressions = self.store.suppression_matcher()?;
+            let suppressed: HashSet<&str> = batch
+                .iter()
+                .filter(|recipient| suppressions.is_suppressed(&recipient.email))
+                .map(|recipient| recipient.member_id.as_str())
+                .collect();
+            if !suppressed.is_empty() {
+                tracing::info!(
+                    "Skipping {} recipients of campaign {} suppressed since it was queued",
+                    suppressed.len(),
+                    campaign.id
+                );
+                self.store.update_recipients(&campaign.id, |recipient| {
+                    (recipient.status == RecipientStatus::Pending
+                        && suppressed.contains(recipient.member_id.as_str()))
+                    .then(|| RecipientStatus::Skipped {
+                        reason: "suppressed".to_string(),
+                    })
+                });
+                continue;
+            }
+
+            // Welcome steps wait days after signup, by when members may have
+            // unsubscribed through Ghost or been deleted
+            if campaign.welcome && !batch.is_empty() {
+                let member_ids: Vec<&str> = batch
+                    .iter()
+                    .map(|recipient| recipient.member_id.as_str())
+                    .collect();
+                let subscribed: HashSet<String> =
+                    ghost::fetch_subscribed_members(config, &member_ids)
+                        .await?
+                        .into_iter()
+                        .map(|member| member.id)
+                        .collect();
+                let gone: HashSet<&str> = member_ids
+                    .into_iter()
+                    .filter(|member_id| !subscribed.contains(*member_id))
+                    .collect();
+                if !gone.is_empty() {
+                    tracing::info!(
+                        "Skipping {} recipients of welcome campaign {} no longer subscribed",
+                        gone.len(),
+                        campaign.id
+                    );
+                    self.store.update_recipients(&campaign.id, |recipient| {
+                        (recipient.status == RecipientStatus::Pending
+                            && gone.contains(recipient.member_id.as_str()))
+                        .then(|| RecipientStatus::Skipped {
+                            reason: "unsubscribed".to_string(),
+                        })
+                    });
+                    continue;
+                }
+            }
+
+            if batch.is_empty() && self.store.has_pending_recipients(&campaign.id) {
+                tracing::info!(
+                    "Campaign {} has no recipients due yet, waiting",
+                    campaign.id
+                );
+                return Ok(());
+            }
+            if batch.is_empty() {
+                self.store
+                    .set_campaign_status(&campaign.id, CampaignStatus::Completed);
+                tracing::info!("Campaign {} completed", campaign.id);
+                return Ok(());
+            }
+
+            batch_index += 1;
+            tracing::info!(
+                "Processing batch {} with {} subscribers",
+                batch_index,
+                batch.len()
+            );
+
+            let mut batch_emails = Vec::new();
+            let mut dry_run_emails = Vec::new();
+
+            let dry_run_batch = self.store.dry_run_batches(&campaign.id) + 1;
+
+            // Prepare all emails in this batch
+            for recipient in &batch {
+                tracing::debug!("Preparing email for subscriber: {}", recipient.email);
+
+                let personalized = compiled.personalize(&recipient.member(), config);
+
+                let mut email = CreateEmailBaseOptions::new(
+                    &from,
+                    vec![recipient.email.clone()],
+                    personalized.subject,
+                )
+                .with_html(&personalized.html)
+                .with_header("List-Id", &list_id);
+
+                if let Some(reply_to) = &reply_to {
+                    email = email.with_reply(reply_to);
+                }
+
+                for (name, value) in
+                    unsubscribe::list_unsubscribe_headers(config, &recipient.member_uuid)
+                {
+                    email = email.with_header(name, &value);
+                }
+
+                if campaign.dry_run {
+                    dry_run_emails.push(DryRunEmail::new(&email, dry_run_batch)?);
+                }
+                batch_emails.push(email);
+            }
+
+            // Send the entire batch using BatchSvc
+            let statuses: HashMap<String, RecipientStatus> =
+                match mailer.send_batch(batch_emails).await {
+                    Ok(email_ids) if campaign.dry_run => {
+                        let statuses = email_ids
+                            .iter()
+                            .zip(&batch)
+                            .map(|(email_id, recipient)| {
+                                (
+                                    recipient.member_id.clone(),
+                                    RecipientStatus::Sent {
+                                        email_id: email_id.clone(),
+                                    },
+                                )
+                            })
+                            .collect();
+                        // Dry runs stay out of sends, and with it analytics and Ghost
+                        for (email, email_id) in dry_run_emails.iter_mut().zip(email_ids) {
+                            email.email_id = email_id;
+                        }
+                        self.store.record_dry_run(&campaign.id, dry_run_emails);
+                        statuses
+                    }
+                    Ok(email_ids) => {
+                        tracing::info!(
+                            "Successfully sent batch {} ({} emails)",
+                            batch_index,
+                            email_ids.len()
+                        );
+                        // Resend returns IDs in the same order as the batch
+                        let sent_at = Utc::now();
+                        let sends = email_ids
+                            .into_iter()
+                            .zip(&batch)
+                            .map(|(email_id, recipient)| {
+                                tracing::debug!("Email sent with ID: {}", email_id);
+                                SendRecord {
+                                    email_id,
+                                    campaign_id: campaign.id.clone(),
+                                    member_id: recipient.member_id.clone(),
+                                    member_uuid: recipient.member_uuid.clone(),
+                                    email: recipient.email.clone(),
+                                    sent_at,
+                                    events: Vec::new(),
+                                    opens: Vec::new(),
+                                    clicks: Vec::new(),
+                                }
+                            })
+                            .collect::<Vec<_>>();
+
+                        let mut statuses: HashMap<String, RecipientStatus> = sends
+                            .iter()
+                            .map(|send| {
+                                (
+                                    send.member_id.clone(),
+                                    RecipientStatus::Sent {
+                                        email_id: send.email_id.clone(),
+                                    },
+                                )
+                            })
+                            .collect();
+
+                        // Recipients without an ID can't be tracked, and sending to
+                        // them again could mail them twice
+                        if sends.len() != batch.len() {
+                            tracing::error!(
+                                "Resend returned {} email IDs for batch {} of {} emails",
+                                sends.len(),
+                                batch_index,
+                                batch.len()
+                            );
+                            for recipient in batch.iter().skip(sends.len()) {
+                                statuses.insert(
+                                    recipient.member_id.clone(),
+                                    RecipientStatus::Failed {
+                                        error: "Resend returned no email ID".to_string(),
+                                    },
+                                );
+                            }
+                        }
+                        self.store.record_sends(sends);
+                        statuses
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to send batch {}: {}", batch_index, e);
+                        batch
+                            .iter()
+                            .map(|recipient| {
+                                (
+                                    recipient.member_id.clone(),
+                                    RecipientStatus::Failed {
+                                        error: e.to_string(),
+                                    },
+                                )
+                            })
+                            .collect()
+                    }
+                };
+
+            self.store.update_recipients(&campaign.id, |recipient| {
+                statuses.get(&recipient.member_id).cloned()
+            });
+
+            // A restart must not send a batch Resend already accepted, so get its
+            // statuses to disk before the next one
+            self.store.flush()?;
+
+            // Sleep between batches to respect rate limits
+            if !campaign.dry_run {
+                tokio::time::sleep(BATCH_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use crate::{
+    config::Config,
+    store::{DeliveryEvent, EventKind, Store},
+    suppression::{self, SuppressionReason},
+};
+use axum::{
+    body::{to_bytes, Body},
+    extract::State,
+    http::{HeaderMap, Request, StatusCode},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+// Resend events are small, 1MB is plenty
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// Reject events signed more than 5 minutes away from now to limit replays
+const TIMESTAMP_TOLERANCE_SECS: i64 = 5 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct ResendEvent {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub created_at: DateTime<Utc>,
+    pub data: ResendEventData,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendEventData {
+    pub email_id: String,
+    #[serde(default)]
+    pub to: Vec<String>,
+    pub click: Option<ResendClick>,
+    pub bounce: Option<ResendBounce>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendClick {
+    pub link: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendBounce {
+    pub message: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+}
+
+impl ResendEvent {
+    fn event_kind(&self) -> Option<EventKind> {
+        match self.kind.as_str() {
+            "email.sent" => Some(EventKind::Sent),
+            "email.delivered" => Some(EventKind::Delivered),
+            "email.delivery_delayed" => Some(EventKind::DeliveryDelayed),
+            "email.bounced" => Some(EventKind::Bounced),
+            "email.complained" => Some(EventKind::Complained),
+            "email.opened" => Some(EventKind::Opened),
+            "email.clicked" => Some(EventKind::Clicked),
+            _ => None,
+        }
+    }
+
+    /// Soft bounces (full mailbox, temporary failures) shouldn't suppress anyone.
+    fn is_transient_bounce(&self) -> bool {
+        self.data
+            .bounce
+            .as_ref()
+            .and_then(|bounce| bounce.kind.as_deref())
+            .is_some_and(|kind| kind.eq_ignore_ascii_case("transient"))
+    }
+
+    fn detail(&self) -> Option<String> {
+        if let Some(click) = &self.data.click {
+            return Some(click.link.clone());
+        }
+        self.data.bounce.as_ref().map(|bounce| {
+            format!(
+                "{}: {}",
+                bounce.kind.as_deref().unwrap_or("Unknown"),
+                bounce.message.as_deref().unwrap_or("")
+            )
+        })
+    }
+}
+
+/// Receives Resend delivery events and records them against the stored send.
+pub async fn handle_resend_webhook(
+    State(config): State<Config>,
+    State(store): State<Store>,
+    request: Request<Body>,
+) -> Result<StatusCode, StatusCode> {
+    let secret = config.resend_webhook_secret.as_deref().ok_or_else(|| {
+        tracing::warn!("Received Resend webhook but RESEND_WEBHOOK_SECRET is not configured");
+        StatusCode::NOT_FOUND
+    })?;
+
+    let headers = request.headers().clone();
+    let body_bytes = to_bytes(request.into_body(), MAX_BODY_SIZE)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to read request body: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+
+    verify_svix_signature(secret, &headers, &body_bytes)?;
+
+    let event: ResendEvent = serde_json::from_slice(&body_bytes).map_err(|e| {
+        tracing::error!("Failed to parse Resend event: {}", e);
+        StatusCode::BAD_REQUEST
+    })?;
+
+    let Some(kind) = event.event_kind() else {
+        tracing::debug!("Ignoring Resend event of type {}", event.kind);
+        return Ok(StatusCode::OK);
+    };
+
+    let delivery_event = DeliveryEvent {
+        kind,
+        at: event.created_at,
+        detail: event.detail(),
+    };
+
+    let Some(send) = store.record_event(&event.data.email_id, delivery_event) else {
+        // Emails sent by something other than this service share the Resend account
+        tracing::debug!(
+            "Ignoring {} for unknown email {} to {:?}",
+            event.kind,
+            event.data.email_id,
+            event.data.to
+        );
+        return Ok(StatusCode::OK);
+    };
+
+    tracing::info!(
+        "Recorded {} for {} (campaign {})",
+        event.kind,
+        send.email,
+        send.campaign_id
+    );
+
+    // Stop mailing addresses that hard bounce or mark us as spam
+    let reason = match kind {
+        EventKind::Bounced if !event.is_transient_bounce() => Some(SuppressionReason::Bounced),
+        EventKind::Complained => Some(SuppressionReason::Complained),
+        _ => None,
+    };
+    if let Some(reason) = reason {
+        suppression::suppress_member(&config, &store, &send, reason, event.detail()).await;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Verifies the Svix signature Resend attaches to every webhook.
+///
+/// The signed content is `{svix-id}.{svix-timestamp}.{body}`, HMAC-SHA256'd with
+/// the base64 part of the `whsec_` secret. The `svix-signature` header holds one
+/// or more space separated `v1,{base64 signature}` entries.
+fn verify_svix_signature(secret: &str, headers: &HeaderMap, body: &[u8]) -> Result<(), StatusCode> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| {
+                tracing::warn!("Missing {} header", name);
+                StatusCode::UNAUTHORIZED
+            })
+    };
+
+    let id = header("svix-id")?;
+    let timestamp = header("svix-timestamp")?;
+    let signatures = header("svix-signature")?;
+
+    let timestamp_secs: i64 = timestamp.parse().map_err(|_| {
+        tracing::warn!("Invalid svix-timestamp header: {}", timestamp);
+        StatusCode::BAD_REQUEST
+    })?;
+    if (Utc::now().timestamp() - timestamp_secs).abs() > TIMESTAMP_TOLERANCE_SECS {
+        tracing::warn!("Resend webhook timestamp {} is too old", timestamp);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let key = STANDARD
+        .decode(secret.strip_prefix("whsec_").unwrap_or(secret))
+        .map_err(|e| {
+            tracing::error!("RESEND_WEBHOOK_SECRET is not valid base64: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).map_err(|e| {
+        tracing::error!("Failed to create HMAC: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    mac.update(id.as_bytes());
+    mac.update(b".");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    let valid = signatures
+        .split_whitespace()
+        .filter_map(|entry| entry.strip_prefix("v1,"))
+        .filter_map(|signature| STANDARD.decode(signature).ok())
+        .any(|signature| mac.clone().verify_slice(&signature).is_ok());
+
+    if !valid {
+        tracing::warn!("Invalid Resend webhook signature for {}", id);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `whsec_` followed by base64 of the key bytes, as Resend hands it out
+    const SECRET: &str = "whsec_c2VjcmV0LWtleS1mb3ItdGVzdHM=";
+    const BODY: &[u8] = br#"{"type":"email.delivered"}"#;
+
+    fn sign(id: &str, timestamp: i64, body: &[u8]) -> String {
+        let key = STANDARD.decode("c2VjcmV0LWtleS1mb3ItdGVzdHM=").unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+        mac.update(format!("{}.{}.", id, timestamp).as_bytes());
+        mac.update(body);
+        format!("v1,{}", STANDARD.encode(mac.finalize().into_bytes()))
+    }
+
+    fn headers(id: &str, timestamp: i64, signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("svix-id", id.parse().unwrap());
+        headers.insert("svix-timestamp", timestamp.to_string().parse().unwrap());
+        headers.insert("svix-signature", signature.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let now = Utc::now().timestamp();
+        let signature = sign("msg_1", now, BODY);
+        assert_eq!(
+            verify_svix_signature(SECRET, &headers("msg_1", now, &signature), BODY),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn accepts_any_matching_signature_in_the_list() {
+        let now = Utc::now().timestamp();
+        let signature = format!("v1,bm90LWl0 {}", sign("msg_1", now, BODY));
+        assert_eq!(
+            verify_svix_signature(SECRET, &headers("msg_1", now, &signature), BODY),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_body() {
+        let now = Utc::now().timestamp();
+        let signature = sign("msg_1", now, BODY);
+        let tampered = br#"{"type":"email.bounced"}"#;
+        assert_eq!(
+            verify_svix_signature(SECRET, &headers("msg_1", now, &signature), tampered),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn rejects_other_secret() {
+        let now = Utc::now().timestamp();
+        let signature = sign("msg_1", now, BODY);
+        let other = "whsec_b3RoZXIta2V5";
+        assert_eq!(
+            verify_svix_signature(other, &headers("msg_1", now, &signature), BODY),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn rejects_expired_timestamp() {
+        let then = Utc::now().timestamp() - TIMESTAMP_TOLERANCE_SECS - 60;
+        let signature = sign("msg_1", then, BODY);
+        assert_eq!(
+            verify_svix_signature(SECRET, &headers("msg_1", then, &signature), BODY),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn rejects_replay_with_new_timestamp_or_id() {
+        let then = Utc::now().timestamp() - TIMESTAMP_TOLERANCE_SECS - 60;
+        let now = Utc::now().timestamp();
+        let signature = sign("msg_1", then, BODY);
+        assert_eq!(
+            verify_svix_signature(SECRET, &headers("msg_1", now, &signature), BODY),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        let signature = sign("msg_1", now, BODY);
+        assert_eq!(
+            verify_svix_signature(SECRET, &headers("msg_2", now, &signature), BODY),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn rejects_missing_headers() {
+        assert_eq!(
+            verify_svix_signature(SECRET, &HeaderMap::new(), BODY),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+}
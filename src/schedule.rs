@@ -0,0 +1,312 @@
+use crate::{
+    config::Config,
+    ghost::{Member, Post},
+};
+use chrono::{DateTime, FixedOffset, NaiveTime, TimeDelta, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::sync::OnceLock;
+
+/// The site's timezone from Ghost settings, UTC if Ghost names one we don't know.
+pub fn site_timezone(timezone: &str) -> Tz {
+    timezone.parse().unwrap_or_else(|_| {
+        tracing::warn!("Unknown Ghost timezone {}, using UTC", timezone);
+        Tz::UTC
+    })
+}
+
+/// The time of day set by an internal `#email-at-0800` tag on the post.
+pub fn tagged_send_time(post: &Post) -> Option<NaiveTime> {
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    let tag = TAG.get_or_init(|| {
+        Regex::new(r"(?i)^#email-at-(\d{2}):?(\d{2})$").expect("send time tag regex is valid")
+    });
+
+    post.other
+        .get("tags")?
+        .as_array()?
+        .iter()
+        .filter_map(|t| t.get("name")?.as_str())
+        .find_map(|name| {
+            let captures = tag.captures(name.trim())?;
+            NaiveTime::from_hms_opt(captures[1].parse().ok()?, captures[2].parse().ok()?, 0)
+        })
+}
+
+/// When a newly published post should go out, `None` to send right away. A send
+/// time tag wins over `SEND_TIME`, which wins over `SEND_DELAY_MINUTES`.
+pub fn send_at(
+    post: &Post,
+    config: &Config,
+    timezone: Tz,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    if let Some(time) = tagged_send_time(post).or(config.send_time) {
+        return Some(next_local_time(time, &timezone, now));
+    }
+    let delay = TimeDelta::from_std(config.send_delay?).ok()?;
+    Some(now + delay)
+}
+
+/// The next moment after `now` the clock in `timezone` reads `time`.
+pub fn next_local_time<Z: TimeZone>(
+    time: NaiveTime,
+    timezone: &Z,
+    now: DateTime<Utc>,
+) -> DateTime<Utc> {
+    let today = now.with_timezone(timezone).date_naive();
+
+    (0..=2)
+        .filter_map(|days| today.checked_add_signed(TimeDelta::days(days)))
+        .filter_map(|date| {
+            let local = date.and_time(time);
+            // A time skipped by a DST change happens an hour later that day
+            timezone.from_local_datetime(&local).earliest().or_else(|| {
+                timezone
+                    .from_local_datetime(&(local + TimeDelta::hours(1)))
+                    .earliest()
+            })
+        })
+        .map(|at| at.with_timezone(&Utc))
+        .find(|at| *at > now)
+        .unwrap_or(now)
+}
+
+// Members need this many opens or clicks before their timezone is guessed from them
+const MIN_ENGAGEMENT_FOR_INFERENCE: usize = 3;
+
+// Hour of the day people are assumed to read email on average, in their own time
+const TYPICAL_LOCAL_READ_HOUR: f64 = 12.0;
+
+/// Where a member is, as far as we can tell.
+#[derive(Debug, Clone, Copy)]
+pub enum MemberTimezone {
+    /// From a `tz:Europe/Berlin` label or Ghost's geolocation of the member
+    Named(Tz),
+    /// Guessed from when the member opened earlier emails
+    Inferred(FixedOffset),
+}
+
+impl MemberTimezone {
+    /// The member's timezone from, in order, a `tz:` label, Ghost's geolocation
+    /// or the times they opened or clicked earlier emails.
+    pub fn of(member: &Member, engagement: &[DateTime<Utc>]) -> Option<Self> {
+        labelled_timezone(member)
+            .or_else(|| geolocated_timezone(member))
+            .map(MemberTimezone::Named)
+            .or_else(|| inferred_offset(engagement).map(MemberTimezone::Inferred))
+    }
+
+    /// The next moment after `now` the member's clock reads `time`.
+    pub fn next_local_time(&self, time: NaiveTime, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            MemberTimezone::Named(timezone) => next_local_time(time, timezone, now),
+            MemberTimezone::Inferred(offset) => next_local_time(time, offset, now),
+        }
+    }
+}
+
+fn labelled_timezone(member: &Member) -> Option<Tz> {
+    member
+        .other
+        .get("labels")?
+        .as_array()?
+        .iter()
+        .filter_map(|label| label.get("name")?.as_str())
+        .find_map(|name| name.strip_prefix("tz:")?.trim().parse().ok())
+}
+
+/// Ghost stores where a member signed up from as a JSON string.
+fn geolocated_timezone(member: &Member) -> Option<Tz> {
+    let geolocation: serde_json::Value =
+        serde_json::from_str(member.other.get("geolocation")?.as_str()?).ok()?;
+    geolocation.get("timezone")?.as_str()?.parse().ok()
+}
+
+/// Guesses a UTC offset from the average time of day a member engages, assuming
+/// that is around midday for them.
+fn inferred_offset(engagement: &[DateTime<Utc>]) -> Option<FixedOffset> {
+    if engagement.len() < MIN_ENGAGEMENT_FOR_INFERENCE {
+        return None;
+    }
+
+    // Times of day wrap around, so average them as angles on a clock
+    let (sin, cos) = engagement.iter().fold((0.0, 0.0), |(sin, cos), at| {
+        let hours = at.hour() as f64 + at.minute() as f64 / 60.0;
+        let angle = hours / 24.0 * std::f64::consts::TAU;
+        (sin + angle.sin(), cos + angle.cos())
+    });
+    let mean_hour = sin.atan2(cos).rem_euclid(std::f64::consts::TAU) / std::f64::consts::TAU * 24.0;
+
+    let mut offset = (TYPICAL_LOCAL_READ_HOUR - mean_hour).round() as i32;
+    if offset > 14 {
+        offset -= 24;
+    } else if offset < -12 {
+        offset += 24;
+    }
+    FixedOffset::east_opt(offset * 3600)
+}
+
+/// Reads a send time given as RFC 3339, e.g. `2026-01-05T08:00:00+01:00`, or
+/// `now` to send right away.
+pub fn deserialize_send_at<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    if value.eq_ignore_ascii_case("now") {
+        return Ok(Some(Utc::now()));
+    }
+    DateTime::parse_from_rfc3339(&value)
+        .map(|send_at| Some(send_at.with_timezone(&Utc)))
+        .map_err(|e| serde::de::Error::custom(format!("invalid send_at '{}': {}", value, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::{America::New_York, Europe::Berlin};
+
+    fn utc(at: &str) -> DateTime<Utc> {
+        at.parse().unwrap()
+    }
+
+    fn time(at: &str) -> NaiveTime {
+        at.parse().unwrap()
+    }
+
+    fn offset_hours(engagement: &[&str]) -> Option<i32> {
+        let engagement: Vec<DateTime<Utc>> = engagement.iter().map(|at| utc(at)).collect();
+        inferred_offset(&engagement).map(|offset| offset.local_minus_utc() / 3600)
+    }
+
+    #[test]
+    fn later_today() {
+        let now = utc("2026-06-01T12:00:00Z"); // 08:00 in New York
+        assert_eq!(
+            next_local_time(time("14:00"), &New_York, now),
+            utc("2026-06-01T18:00:00Z")
+        );
+    }
+
+    #[test]
+    fn already_passed_today() {
+        let now = utc("2026-06-01T15:00:00Z"); // 11:00 in New York
+        assert_eq!(
+            next_local_time(time("08:00"), &New_York, now),
+            utc("2026-06-02T12:00:00Z")
+        );
+    }
+
+    #[test]
+    fn right_now_is_tomorrow() {
+        let now = utc("2026-06-01T12:00:00Z");
+        assert_eq!(
+            next_local_time(time("08:00"), &New_York, now),
+            utc("2026-06-02T12:00:00Z")
+        );
+    }
+
+    #[test]
+    fn spring_forward_gap_moves_an_hour_later() {
+        // Berlin skips from 02:00 to 03:00 on 29 March 2026
+        let now = utc("2026-03-29T00:00:00Z");
+        assert_eq!(
+            next_local_time(time("02:30"), &Berlin, now),
+            utc("2026-03-29T01:30:00Z")
+        );
+    }
+
+    #[test]
+    fn fall_back_overlap_takes_the_first() {
+        // Berlin goes through 02:00 to 03:00 twice on 25 October 2026
+        let now = utc("2026-10-24T22:00:00Z");
+        assert_eq!(
+            next_local_time(time("02:30"), &Berlin, now),
+            utc("2026-10-25T00:30:00Z")
+        );
+    }
+
+    #[test]
+    fn fall_back_overlap_after_the_first() {
+        // Between the two 02:30s the next one is the day after, not an hour later
+        let now = utc("2026-10-25T01:00:00Z");
+        assert_eq!(
+            next_local_time(time("02:30"), &Berlin, now),
+            utc("2026-10-26T01:30:00Z")
+        );
+    }
+
+    #[test]
+    fn fixed_offset() {
+        let offset = FixedOffset::east_opt(-5 * 3600).unwrap();
+        let now = utc("2026-01-10T20:00:00Z"); // 15:00 at UTC-5
+        assert_eq!(
+            next_local_time(time("08:00"), &offset, now),
+            utc("2026-01-11T13:00:00Z")
+        );
+    }
+
+    #[test]
+    fn no_inference_from_too_little_engagement() {
+        assert_eq!(
+            offset_hours(&["2026-01-01T12:00:00Z", "2026-01-02T12:00:00Z"]),
+            None
+        );
+    }
+
+    #[test]
+    fn infers_offset_from_reading_around_midday() {
+        let engagement = [
+            "2026-01-01T16:30:00Z",
+            "2026-01-02T17:00:00Z",
+            "2026-01-03T17:30:00Z",
+        ];
+        assert_eq!(offset_hours(&engagement), Some(-5));
+
+        let engagement = [
+            "2026-01-01T02:30:00Z",
+            "2026-01-02T03:00:00Z",
+            "2026-01-03T03:30:00Z",
+        ];
+        assert_eq!(offset_hours(&engagement), Some(9));
+    }
+
+    #[test]
+    fn averages_across_midnight() {
+        // Averaging hours would land on midday UTC, the clock average is midnight
+        let engagement = [
+            "2026-01-01T23:00:00Z",
+            "2026-01-02T00:00:00Z",
+            "2026-01-03T01:00:00Z",
+        ];
+        assert_eq!(offset_hours(&engagement), Some(12));
+    }
+
+    #[test]
+    fn wraps_offsets_into_range() {
+        // Midday at UTC+13 and UTC-11 are the same moment, the offset stays in range
+        let engagement = [
+            "2026-01-01T22:30:00Z",
+            "2026-01-02T23:00:00Z",
+            "2026-01-03T23:30:00Z",
+        ];
+        assert_eq!(offset_hours(&engagement), Some(-11));
+
+        let engagement = [
+            "2026-01-01T00:30:00Z",
+            "2026-01-02T00:45:00Z",
+            "2026-01-03T01:00:00Z",
+        ];
+        assert_eq!(offset_hours(&engagement), Some(11));
+
+        for hour in 0..24 {
+            let engagement: Vec<DateTime<Utc>> = (1..=3)
+                .map(|day| Utc.with_ymd_and_hms(2026, 1, day, hour, 0, 0).unwrap())
+                .collect();
+            let offset = inferred_offset(&engagement).unwrap().local_minus_utc() / 3600;
+            assert!((-12..=14).contains(&offset), "{} for {}:00", offset, hour);
+        }
+    }
+}
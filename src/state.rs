@@ -0,0 +1,10 @@
+use crate::{config::Config, queue::Queue, store::Store};
+use axum::extract::FromRef;
+
+/// Shared state for all handlers. Handlers extract only the parts they need.
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub config: Config,
+    pub store: Store,
+    pub queue: Queue,
+}
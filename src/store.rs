@@ -0,0 +1,1061 @@
+use crate::engagement::MemberEngagement;
+use crate::ghost::{Member, Post};
+use crate::mailer::DryRunEmail;
+use crate::suppression::{Suppression, SuppressionKind, SuppressionMatcher};
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+const STORE_FILE: &str = "store.json";
+const LOCK_FILE: &str = "store.lock";
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Finished campaigns keep their posts and dry run emails this long, for retrying
+// failed recipients and reading dry run reports, then only their stats are kept
+const PRUNE_AFTER: chrono::TimeDelta = chrono::TimeDelta::days(30);
+
+// Repeat opens of the same kind within this window are one open (reloads, clients
+// fetching images twice)
+const OPEN_DEDUP_WINDOW: chrono::TimeDelta = chrono::TimeDelta::minutes(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CampaignStatus {
+    /// Waiting for the queue to get to it
+    Queued,
+    Sending,
+    Completed,
+    Cancelled,
+}
+
+/// A campaign is one post sent to the list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Campaign {
+    pub id: String,
+    pub post_id: String,
+    pub post_title: String,
+    pub created_at: DateTime<Utc>,
+    pub status: CampaignStatus,
+    /// The post as it was when the campaign was created, rendered by the queue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post: Option<Post>,
+    /// Run without sending anything, see `DryRunReport`
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Held in the queue until then, `None` to send right away
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_at: Option<DateTime<Utc>>,
+    /// The posts of a digest campaign, which has no single `post`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<Vec<Post>>,
+    /// A step of the welcome sequence, which gains recipients as members sign up
+    #[serde(default)]
+    pub welcome: bool,
+    /// Attempts in a row that failed, e.g. because the post couldn't be rendered
+    #[serde(default)]
+    pub failures: u32,
+    /// After a failed attempt the queue leaves the campaign alone until then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_at: Option<DateTime<Utc>>,
+    /// When the campaign was last completed or cancelled, `None` while active
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl Campaign {
+    /// Whether the queue still has work to do for the campaign.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self.status,
+            CampaignStatus::Queued | CampaignStatus::Sending
+        )
+    }
+
+    /// Whether the campaign's scheduled time has come.
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.send_at.map_or(true, |send_at| send_at <= now)
+    }
+}
+
+/// A campaign with its recipients counted by status.
+#[derive(Debug, Serialize)]
+pub struct CampaignSummary {
+    pub id: String,
+    pub post_id: String,
+    pub post_title: String,
+    pub created_at: DateTime<Utc>,
+    pub status: CampaignStatus,
+    pub dry_run: bool,
+    pub welcome: bool,
+    pub send_at: Option<DateTime<Utc>>,
+    pub recipients: usize,
+    pub pending: usize,
+    pub sent: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+impl CampaignSummary {
+    pub fn new(campaign: Campaign, recipients: &[Recipient]) -> Self {
+        let count = |matches: fn(&RecipientStatus) -> bool| {
+            recipients
+                .iter()
+                .filter(|recipient| matches(&recipient.status))
+                .count()
+        };
+
+        CampaignSummary {
+            pending: count(|status| matches!(status, RecipientStatus::Pending)),
+            sent: count(|status| matches!(status, RecipientStatus::Sent { .. })),
+            failed: count(|status| matches!(status, RecipientStatus::Failed { .. })),
+            skipped: count(|status| matches!(status, RecipientStatus::Skipped { .. })),
+            recipients: recipients.len(),
+            id: campaign.id,
+            post_id: campaign.post_id,
+            post_title: campaign.post_title,
+            created_at: campaign.created_at,
+            status: campaign.status,
+            dry_run: campaign.dry_run,
+            welcome: campaign.welcome,
+            send_at: campaign.send_at,
+        }
+    }
+}
+
+/// A member a campaign goes to, snapshotted when the campaign is created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recipient {
+    pub member_id: String,
+    pub member_uuid: String,
+    pub email: String,
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub status: RecipientStatus,
+    /// Held until then when delivering at each recipient's local time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RecipientStatus {
+    Pending,
+    Sent { email_id: String },
+    Failed { error: String },
+    Skipped { reason: String },
+}
+
+impl Recipient {
+    pub fn new(member: &Member, status: RecipientStatus) -> Self {
+        Recipient {
+            member_id: member.id.clone(),
+            member_uuid: member.uuid.clone(),
+            email: member.email.clone(),
+            name: member.name.clone(),
+            status,
+            send_at: None,
+        }
+    }
+
+    /// Whether the recipient is still to be sent to and their time has come.
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.status == RecipientStatus::Pending
+            && self.send_at.map_or(true, |send_at| send_at <= now)
+    }
+
+    /// The member as far as rendering an email needs it.
+    pub fn member(&self) -> Member {
+        Member {
+            id: self.member_id.clone(),
+            uuid: self.member_uuid.clone(),
+            email: self.email.clone(),
+            name: self.name.clone(),
+            status: String::new(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            other: serde_json::Value::Null,
+        }
+    }
+}
+
+/// One email handed to Resend, keyed in the store by Resend's email ID.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendRecord {
+    pub email_id: String,
+    pub campaign_id: String,
+    pub member_id: String,
+    pub member_uuid: String,
+    pub email: String,
+    pub sent_at: DateTime<Utc>,
+    #[serde(default)]
+    pub events: Vec<DeliveryEvent>,
+    /// Opens recorded by our own tracking pixel
+    #[serde(default)]
+    pub opens: Vec<Open>,
+    /// Clicks recorded by our own redirect links
+    #[serde(default)]
+    pub clicks: Vec<Click>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Sent,
+    Delivered,
+    DeliveryDelayed,
+    Bounced,
+    Complained,
+    Opened,
+    Clicked,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryEvent {
+    pub kind: EventKind,
+    pub at: DateTime<Utc>,
+    /// Clicked link or bounce message, depending on the event
+    pub detail: Option<String>,
+}
+
+/// Who fetched the tracking pixel, as far as we can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenKind {
+    /// A mail client loading images directly
+    Direct,
+    /// Gmail's image proxy, which fetches when the message is opened
+    GmailProxy,
+    /// Apple Mail Privacy Protection, which prefetches whether or not it's read
+    AppleMpp,
+    /// Security scanners and link previewers
+    Bot,
+}
+
+impl OpenKind {
+    /// Whether the open likely means a person looked at the email.
+    pub fn is_human(self) -> bool {
+        matches!(self, OpenKind::Direct | OpenKind::GmailProxy)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Open {
+    pub kind: OpenKind,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Click {
+    pub url: String,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StoreData {
+    #[serde(default)]
+    pub campaigns: HashMap<String, Campaign>,
+    #[serde(default)]
+    pub sends: HashMap<String, SendRecord>,
+    /// Suppressed addresses, domains and patterns, keyed by `Suppression::key`
+    #[serde(default)]
+    pub suppressions: HashMap<String, Suppression>,
+    /// Engagement last pushed to Ghost, by member ID
+    #[serde(default)]
+    pub ghost_engagement: HashMap<String, MemberEngagement>,
+    /// Recipients of each campaign, by campaign ID
+    #[serde(default)]
+    pub recipients: HashMap<String, Vec<Recipient>>,
+    /// Whether the send queue is paused
+    #[serde(default)]
+    pub queue_paused: bool,
+    /// What dry run campaigns would have sent, by campaign ID
+    #[serde(default)]
+    pub dry_run_emails: HashMap<String, Vec<DryRunEmail>>,
+    /// End of the window the last digest covered, the next one starts there
+    #[serde(default)]
+    pub last_digest_at: Option<DateTime<Utc>>,
+    /// Resend email IDs in `sends` by campaign ID and member UUID, for tracking
+    /// links that only carry those
+    #[serde(skip)]
+    send_index: HashMap<(String, String), String>,
+}
+
+fn send_key(send: &SendRecord) -> ((String, String), String) {
+    (
+        (send.campaign_id.clone(), send.member_uuid.clone()),
+        send.email_id.clone(),
+    )
+}
+
+fn latest_post_campaign<'a>(
+    data: &'a StoreData,
+    post_id: &str,
+    dry_run: bool,
+) -> Option<&'a Campaign> {
+    data.campaigns
+        .values()
+        .filter(|campaign| {
+            campaign.post_id == post_id
+                && campaign.dry_run == dry_run
+                && !campaign.welcome
+                && campaign.status != CampaignStatus::Cancelled
+        })
+        .max_by_key(|campaign| campaign.created_at)
+}
+
+/// Whether the queue can work on a campaign now: it is active, due, not waiting
+/// to be retried, and has recipients due or none left to send to.
+fn is_ready(data: &StoreData, campaign: &Campaign, now: DateTime<Utc>) -> bool {
+    if !campaign.is_active() || !campaign.is_due(now) {
+        return false;
+    }
+    if campaign.retry_at.is_some_and(|at| at > now) {
+        return false;
+    }
+
+    let recipients = data.recipients.get(&campaign.id).into_iter().flatten();
+    let mut pending = recipients
+        .filter(|recipient| recipient.status == RecipientStatus::Pending)
+        .peekable();
+    pending.peek().is_none() || pending.any(|recipient| recipient.is_due(now))
+}
+
+fn load(path: &Path) -> anyhow::Result<StoreData> {
+    let mut data: StoreData = match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|e| anyhow::anyhow!("Failed to parse store at {}: {}", path.display(), e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoreData::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    data.send_index = data.sends.values().map(send_key).collect();
+    Ok(data)
+}
+
+/// Campaign and delivery state, kept in memory and periodically flushed to a JSON
+/// file in the data directory.
+#[derive(Clone)]
+pub struct Store {
+    data: Arc<RwLock<StoreData>>,
+    path: PathBuf,
+    dirty: Arc<AtomicBool>,
+    /// Held while this process owns the store, see `claim`
+    lock: Arc<Mutex<Option<File>>>,
+}
+
+impl Store {
+    /// Loads the store from `data_dir`, starting empty when no file exists yet.
+    pub fn open(data_dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(STORE_FILE);
+
+        let data = load(&path)?;
+
+        tracing::info!("Loaded store from {}", path.display());
+
+        Ok(Store {
+            data: Arc::new(RwLock::new(data)),
+            path,
+            dirty: Arc::new(AtomicBool::new(false)),
+            lock: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Takes an exclusive lock on the store, held until `release` or the process
+    /// exits, so the server and CLI commands never write to it at once. Returns
+    /// `false` if another process holds it.
+    pub fn claim(&self) -> anyhow::Result<bool> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.lock_path())?;
+        if file.try_lock_exclusive().is_err() {
+            return Ok(false);
+        }
+
+        // Only for telling users who has it, the lock is what counts
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+
+        // Whoever held it before may have written since the store was opened
+        let data = load(&self.path)?;
+        *self.data.write().expect("store lock poisoned") = data;
+
+        *self.lock.lock().expect("store lock poisoned") = Some(file);
+        Ok(true)
+    }
+
+    pub fn release(&self) {
+        // Closing the file gives up the lock
+        self.lock.lock().expect("store lock poisoned").take();
+    }
+
+    /// The process ID of another process holding the store, if any.
+    pub fn owner(&self) -> Option<String> {
+        if self.lock.lock().expect("store lock poisoned").is_some() {
+            return None;
+        }
+        let file = File::open(self.lock_path()).ok()?;
+        if file.try_lock_shared().is_ok() {
+            let _ = file.unlock();
+            return None;
+        }
+        let pid = std::fs::read_to_string(self.lock_path()).unwrap_or_default();
+        Some(pid.trim().to_string())
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        self.path.with_file_name(LOCK_FILE)
+    }
+
+    /// Runs `f` with read access to the store.
+    pub fn read<T>(&self, f: impl FnOnce(&StoreData) -> T) -> T {
+        f(&self.data.read().expect("store lock poisoned"))
+    }
+
+    /// Runs `f` with write access to the store and marks it for flushing.
+    pub fn update<T>(&self, f: impl FnOnce(&mut StoreData) -> T) -> T {
+        let result = f(&mut self.data.write().expect("store lock poisoned"));
+        self.dirty.store(true, Ordering::SeqCst);
+        result
+    }
+
+    /// Writes the store to disk if anything changed since the last flush.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let bytes = self.read(serde_json::to_vec)?;
+
+        // Write to a temporary file first so a crash never leaves a truncated store
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        tracing::debug!("Flushed store to {}", self.path.display());
+        Ok(())
+    }
+
+    /// Drops the posts and dry run emails of campaigns finished more than
+    /// `PRUNE_AFTER` ago, which would otherwise make every flush slower. Returns
+    /// how many campaigns were pruned.
+    pub fn prune(&self) -> usize {
+        let now = Utc::now();
+        let cutoff = now - PRUNE_AFTER;
+        let prunable = |data: &StoreData, campaign: &Campaign| {
+            !campaign.is_active()
+                && campaign.finished_at.is_some_and(|at| at < cutoff)
+                && (campaign.post.is_some()
+                    || campaign.digest.is_some()
+                    || data.dry_run_emails.contains_key(&campaign.id))
+        };
+        // Stores from before finish times were recorded don't say when their
+        // campaigns finished, so they are kept for the full period from now
+        let unrecorded =
+            |campaign: &Campaign| !campaign.is_active() && campaign.finished_at.is_none();
+
+        // Most of the time there is nothing to prune, don't mark the store dirty then
+        let (ids, has_unrecorded) = self.read(|data| {
+            let ids: Vec<String> = data
+                .campaigns
+                .values()
+                .filter(|campaign| prunable(data, campaign))
+                .map(|campaign| campaign.id.clone())
+                .collect();
+            (ids, data.campaigns.values().any(unrecorded))
+        });
+        if ids.is_empty() && !has_unrecorded {
+            return 0;
+        }
+
+        self.update(|data| {
+            for campaign in data.campaigns.values_mut() {
+                if unrecorded(campaign) {
+                    campaign.finished_at = Some(now);
+                }
+            }
+            for id in &ids {
+                if let Some(campaign) = data.campaigns.get_mut(id) {
+                    campaign.post = None;
+                    campaign.digest = None;
+                }
+                data.dry_run_emails.remove(id);
+            }
+        });
+        if ids.is_empty() {
+            return 0;
+        }
+        tracing::info!("Pruned {} finished campaigns", ids.len());
+        ids.len()
+    }
+
+    /// Prunes and flushes the store in the background every few seconds.
+    pub fn spawn_flush_task(&self) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                store.prune();
+                if let Err(e) = store.flush() {
+                    tracing::error!("Failed to flush store: {}", e);
+                }
+            }
+        });
+    }
+
+    pub fn insert_campaign(&self, campaign: Campaign, recipients: Vec<Recipient>) {
+        self.update(|data| {
+            data.recipients.insert(campaign.id.clone(), recipients);
+            data.campaigns.insert(campaign.id.clone(), campaign);
+        });
+    }
+
+    pub fn campaign(&self, campaign_id: &str) -> Option<Campaign> {
+        self.read(|data| data.campaigns.get(campaign_id).cloned())
+    }
+
+    /// All campaigns, newest first.
+    pub fn campaigns(&self) -> Vec<Campaign> {
+        let mut campaigns: Vec<Campaign> =
+            self.read(|data| data.campaigns.values().cloned().collect());
+        campaigns.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        campaigns
+    }
+
+    /// The latest campaign for a post that wasn't cancelled. Dry runs only count
+    /// against other dry runs, so trying a post out never stops it from being sent.
+    pub fn campaign_for_post(&self, post_id: &str, dry_run: bool) -> Option<Campaign> {
+        self.read(|data| latest_post_campaign(data, post_id, dry_run).cloned())
+    }
+
+    /// Inserts a post's campaign unless the post already has one, checked under
+    /// the same lock so two deliveries of a webhook can't both send it. Returns
+    /// the campaign that got there first.
+    pub fn insert_post_campaign(
+        &self,
+        campaign: Campaign,
+        recipients: Vec<Recipient>,
+    ) -> Result<(), Campaign> {
+        self.update(|data| {
+            if let Some(existing) = latest_post_campaign(data, &campaign.post_id, campaign.dry_run)
+            {
+                return Err(existing.clone());
+            }
+            data.recipients.insert(campaign.id.clone(), recipients);
+            data.campaigns.insert(campaign.id.clone(), campaign);
+            Ok(())
+        })
+    }
+
+    /// The welcome campaign new members are added to for a step's post. A
+    /// cancelled one stops the step until it is resumed or a new one is created.
+    pub fn welcome_campaign(&self, post_id: &str, dry_run: bool) -> Option<Campaign> {
+        self.read(|data| {
+            data.campaigns
+                .values()
+                .filter(|campaign| {
+                    campaign.welcome
+                        && campaign.post_id == post_id
+                        && campaign.dry_run == dry_run
+                        && campaign.status != CampaignStatus::Cancelled
+                })
+                .max_by_key(|campaign| campaign.created_at)
+                .cloned()
+        })
+    }
+
+    /// The oldest campaign the queue has work to do for now: recipients that are
+    /// due, or none left to send to so it can be completed.
+    pub fn next_active_campaign(&self) -> Option<Campaign> {
+        let now = Utc::now();
+        self.read(|data| {
+            data.campaigns
+                .values()
+                .filter(|campaign| is_ready(data, campaign, now))
+                .min_by_key(|campaign| campaign.created_at)
+                .cloned()
+        })
+    }
+
+    /// The campaign, if the queue can work on it now.
+    pub fn ready_campaign(&self, campaign_id: &str) -> Option<Campaign> {
+        let now = Utc::now();
+        self.read(|data| {
+            data.campaigns
+                .get(campaign_id)
+                .filter(|campaign| is_ready(data, campaign, now))
+                .cloned()
+        })
+    }
+
+    /// When the next campaign or recipient held for later, or campaign waiting to
+    /// be retried, is due.
+    pub fn next_send_at(&self) -> Option<DateTime<Utc>> {
+        self.read(|data| {
+            let campaigns = data
+                .campaigns
+                .values()
+                .filter(|campaign| campaign.status == CampaignStatus::Queued)
+                .filter_map(|campaign| campaign.send_at);
+            let recipients = data
+                .campaigns
+                .values()
+                .filter(|campaign| campaign.is_active())
+                .filter_map(|campaign| data.recipients.get(&campaign.id))
+                .flatten()
+                .filter(|recipient| recipient.status == RecipientStatus::Pending)
+                .filter_map(|recipient| recipient.send_at);
+            let retries = data
+                .campaigns
+                .values()
+                .filter(|campaign| campaign.is_active())
+                .filter_map(|campaign| campaign.retry_at);
+            campaigns.chain(recipients).chain(retries).min()
+        })
+    }
+
+    /// Replaces the post a campaign renders, so pending recipients get the latest
+    /// version. Returns `false` if the campaign doesn't exist.
+    pub fn set_campaign_post(&self, campaign_id: &str, post: Post) -> bool {
+        self.update(|data| match data.campaigns.get_mut(campaign_id) {
+            Some(campaign) => {
+                campaign.post_title = post.title.clone();
+                campaign.post = Some(post);
+                true
+            }
+            None => false,
+        })
+    }
+
+    /// Adds a recipient to an existing campaign, reopening it if it was
+    /// completed. Returns `false` if the member is already a recipient.
+    pub fn add_recipient(&self, campaign_id: &str, recipient: Recipient) -> bool {
+        self.update(|data| {
+            let recipients = data.recipients.entry(campaign_id.to_string()).or_default();
+            if recipients
+                .iter()
+                .any(|existing| existing.member_id == recipient.member_id)
+            {
+                return false;
+            }
+            recipients.push(recipient);
+
+            if let Some(campaign) = data.campaigns.get_mut(campaign_id) {
+                if campaign.status == CampaignStatus::Completed {
+                    campaign.status = CampaignStatus::Queued;
+                    campaign.finished_at = None;
+                }
+            }
+            true
+        })
+    }
+
+    /// Skips a member in every campaign that hasn't reached them yet, e.g. after
+    /// they unsubscribed. Returns how many recipients were skipped.
+    pub fn skip_pending_recipients(&self, member_id: &str, reason: &str) -> usize {
+        self.update(|data| {
+            let mut skipped = 0;
+            for recipient in data.recipients.values_mut().flatten() {
+                if recipient.member_id == member_id && recipient.status == RecipientStatus::Pending
+                {
+                    recipient.status = RecipientStatus::Skipped {
+                        reason: reason.to_string(),
+                    };
+                    skipped += 1;
+                }
+            }
+            skipped
+        })
+    }
+
+    /// Returns `false` if the campaign doesn't exist. Queueing a campaign again
+    /// gives it a fresh set of attempts.
+    pub fn set_campaign_status(&self, campaign_id: &str, status: CampaignStatus) -> bool {
+        self.update(|data| match data.campaigns.get_mut(campaign_id) {
+            Some(campaign) => {
+                campaign.status = status;
+                campaign.finished_at = (!campaign.is_active()).then(Utc::now);
+                if status == CampaignStatus::Queued {
+                    campaign.failures = 0;
+                    campaign.retry_at = None;
+                }
+                true
+            }
+            None => false,
+        })
+    }
+
+    /// Counts a failed attempt to send a campaign and holds it until `retry_at`.
+    /// Returns the failures in a row so far.
+    pub fn record_campaign_failure(&self, campaign_id: &str, retry_at: DateTime<Utc>) -> u32 {
+        self.update(|data| match data.campaigns.get_mut(campaign_id) {
+            Some(campaign) => {
+                campaign.failures += 1;
+                campaign.retry_at = Some(retry_at);
+                campaign.failures
+            }
+            None => 0,
+        })
+    }
+
+    pub fn clear_campaign_failures(&self, campaign_id: &str) {
+        self.update(|data| {
+            if let Some(campaign) = data.campaigns.get_mut(campaign_id) {
+                campaign.failures = 0;
+                campaign.retry_at = None;
+            }
+        });
+    }
+
+    pub fn campaign_summary(&self, campaign: Campaign) -> CampaignSummary {
+        let recipients = self.recipients(&campaign.id);
+        CampaignSummary::new(campaign, &recipients)
+    }
+
+    /// Reschedules a campaign that hasn't started sending. Returns `false` if
+    /// there is no such campaign.
+    pub fn set_send_at(&self, campaign_id: &str, send_at: Option<DateTime<Utc>>) -> bool {
+        self.update(|data| match data.campaigns.get_mut(campaign_id) {
+            Some(campaign) if campaign.status == CampaignStatus::Queued => {
+                campaign.send_at = send_at;
+                true
+            }
+            _ => false,
+        })
+    }
+
+    pub fn recipients(&self, campaign_id: &str) -> Vec<Recipient> {
+        self.read(|data| {
+            data.recipients
+                .get(campaign_id)
+                .cloned()
+                .unwrap_or_default()
+        })
+    }
+
+    /// Up to `limit` recipients of a campaign that are due to be sent to.
+    pub fn due_recipients(&self, campaign_id: &str, limit: usize) -> Vec<Recipient> {
+        let now = Utc::now();
+        self.read(|data| {
+            data.recipients
+                .get(campaign_id)
+                .into_iter()
+                .flatten()
+                .filter(|recipient| recipient.is_due(now))
+                .take(limit)
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Whether any recipient of a campaign is still to be sent to, due or not.
+    pub fn has_pending_recipients(&self, campaign_id: &str) -> bool {
+        self.read(|data| {
+            data.recipients
+                .get(campaign_id)
+                .into_iter()
+                .flatten()
+                .any(|recipient| recipient.status == RecipientStatus::Pending)
+        })
+    }
+
+    /// Applies `f` to every recipient of a campaign, returning how many it changed.
+    pub fn update_recipients(
+        &self,
+        campaign_id: &str,
+        mut f: impl FnMut(&Recipient) -> Option<RecipientStatus>,
+    ) -> usize {
+        self.update(|data| {
+            let mut changed = 0;
+            for recipient in data.recipients.get_mut(campaign_id).into_iter().flatten() {
+                if let Some(status) = f(recipient) {
+                    recipient.status = status;
+                    changed += 1;
+                }
+            }
+            changed
+        })
+    }
+
+    pub fn record_dry_run(&self, campaign_id: &str, emails: Vec<DryRunEmail>) {
+        self.update(|data| {
+            data.dry_run_emails
+                .entry(campaign_id.to_string())
+                .or_default()
+                .extend(emails)
+        });
+    }
+
+    /// How many batches a dry run campaign went through so far.
+    pub fn dry_run_batches(&self, campaign_id: &str) -> usize {
+        // Emails are recorded a batch at a time, so the last is of the latest batch
+        self.read(|data| {
+            data.dry_run_emails
+                .get(campaign_id)
+                .and_then(|emails| emails.last())
+                .map_or(0, |email| email.batch)
+        })
+    }
+
+    pub fn dry_run_emails(&self, campaign_id: &str) -> Vec<DryRunEmail> {
+        self.read(|data| {
+            data.dry_run_emails
+                .get(campaign_id)
+                .cloned()
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn last_digest_at(&self) -> Option<DateTime<Utc>> {
+        self.read(|data| data.last_digest_at)
+    }
+
+    pub fn set_last_digest_at(&self, at: DateTime<Utc>) {
+        self.update(|data| data.last_digest_at = Some(at));
+    }
+
+    pub fn queue_paused(&self) -> bool {
+        self.read(|data| data.queue_paused)
+    }
+
+    pub fn set_queue_paused(&self, paused: bool) {
+        self.update(|data| data.queue_paused = paused);
+    }
+
+    pub fn record_sends(&self, sends: Vec<SendRecord>) {
+        self.update(|data| {
+            for send in sends {
+                let (key, email_id) = send_key(&send);
+                data.send_index.insert(key, email_id);
+                data.sends.insert(send.email_id.clone(), send);
+            }
+        });
+    }
+
+    /// Attaches a delivery event to the send with the given Resend email ID.
+    /// Returns the updated send, or `None` if the email wasn't sent by us.
+    pub fn record_event(&self, email_id: &str, event: DeliveryEvent) -> Option<SendRecord> {
+        self.update(|data| {
+            let send = data.sends.get_mut(email_id)?;
+            send.events.push(event);
+            Some(send.clone())
+        })
+    }
+
+    /// Finds the send of a campaign to a member.
+    pub fn find_send(&self, campaign_id: &str, member_uuid: &str) -> Option<SendRecord> {
+        let key = (campaign_id.to_string(), member_uuid.to_string());
+        self.read(|data| {
+            let email_id = data.send_index.get(&key)?;
+            data.sends.get(email_id).cloned()
+        })
+    }
+
+    /// When each member opened or clicked an email, by member ID, leaving out
+    /// machine opens.
+    pub fn engagement_times(&self) -> HashMap<String, Vec<DateTime<Utc>>> {
+        let mut times: HashMap<String, Vec<DateTime<Utc>>> = HashMap::new();
+        self.read(|data| {
+            for send in data.sends.values() {
+                let opens = send
+                    .opens
+                    .iter()
+                    .filter(|open| open.kind.is_human())
+                    .map(|open| open.at);
+                let clicks = send.clicks.iter().map(|click| click.at);
+                times
+                    .entry(send.member_id.clone())
+                    .or_default()
+                    .extend(opens.chain(clicks));
+            }
+        });
+        times
+    }
+
+    /// Records a tracking pixel open against a send. Returns `false` if the send is
+    /// unknown or the open repeats a recent one.
+    pub fn record_open(&self, email_id: &str, open: Open) -> bool {
+        // Check before taking the write lock, most repeat fetches are duplicates
+        let duplicate = |send: &SendRecord| {
+            send.opens.iter().any(|previous| {
+                previous.kind == open.kind && open.at - previous.at < OPEN_DEDUP_WINDOW
+            })
+        };
+        if self.read(|data| data.sends.get(email_id).map_or(true, duplicate)) {
+            return false;
+        }
+
+        self.update(|data| match data.sends.get_mut(email_id) {
+            Some(send) if !duplicate(send) => {
+                send.opens.push(open.clone());
+                true
+            }
+            _ => false,
+        })
+    }
+
+    /// Records a click on a tracked link against the send of a campaign to a
+    /// member. Returns the address it was sent to, or `None` if the send is
+    /// unknown.
+    pub fn record_click(
+        &self,
+        campaign_id: &str,
+        member_uuid: &str,
+        click: Click,
+    ) -> Option<String> {
+        let key = (campaign_id.to_string(), member_uuid.to_string());
+        self.update(|data| {
+            let email_id = data.send_index.get(&key)?;
+            let send = data.sends.get_mut(email_id)?;
+            send.clicks.push(click);
+            Some(send.email.clone())
+        })
+    }
+
+    /// Snapshot of the suppression list for checking many addresses at once.
+    pub fn suppression_matcher(&self) -> anyhow::Result<SuppressionMatcher> {
+        self.read(|data| SuppressionMatcher::new(data.suppressions.values()))
+    }
+
+    /// All suppressions, oldest first.
+    pub fn suppressions(&self) -> Vec<Suppression> {
+        let mut suppressions: Vec<Suppression> =
+            self.read(|data| data.suppressions.values().cloned().collect());
+        suppressions.sort_by_key(|s| s.created_at);
+        suppressions
+    }
+
+    /// Adds an entry to the suppression list. Returns `false` if it was already
+    /// there, and fails if a regex would stop the list from compiling.
+    pub fn suppress(&self, suppression: Suppression) -> anyhow::Result<bool> {
+        self.update(|data| {
+            let key = suppression.key();
+            if data.suppressions.contains_key(&key) {
+                return Ok(false);
+            }
+            if suppression.kind == SuppressionKind::Regex {
+                let all = data.suppressions.values().chain([&suppression]);
+                SuppressionMatcher::new(all)?;
+            }
+            data.suppressions.insert(key, suppression);
+            Ok(true)
+        })
+    }
+
+    /// Skips the pending recipients of every campaign whose address is now
+    /// suppressed, so those held for their local time or a later step don't wait
+    /// on an email that won't be sent. Returns how many were skipped.
+    pub fn skip_suppressed_recipients(&self) -> anyhow::Result<usize> {
+        let suppressions = self.suppression_matcher()?;
+        Ok(self.update(|data| {
+            let mut skipped = 0;
+            for recipient in data.recipients.values_mut().flatten() {
+                if recipient.status == RecipientStatus::Pending
+                    && suppressions.is_suppressed(&recipient.email)
+                {
+                    recipient.status = RecipientStatus::Skipped {
+                        reason: "suppressed".to_string(),
+                    };
+                    skipped += 1;
+                }
+            }
+            skipped
+        }))
+    }
+
+    /// Removes an entry from the suppression list. Returns `false` if it wasn't there.
+    pub fn remove_suppression(&self, kind: SuppressionKind, value: &str) -> bool {
+        self.update(|data| {
+            data.suppressions
+                .remove(&Suppression::key_for(kind, value))
+                .is_some()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    fn store() -> Store {
+        let dir = std::env::temp_dir().join(format!("grm-store-{}", uuid::Uuid::new_v4().simple()));
+        Store::open(&dir).unwrap()
+    }
+
+    fn campaign(created_at: DateTime<Utc>, status: CampaignStatus) -> Campaign {
+        Campaign {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            post_id: "digest".to_string(),
+            post_title: "Digest".to_string(),
+            created_at,
+            status,
+            post: None,
+            dry_run: false,
+            send_at: None,
+            digest: Some(Vec::new()),
+            welcome: false,
+            failures: 0,
+            retry_at: None,
+            finished_at: None,
+        }
+    }
+
+    #[test]
+    fn prunes_by_finish_time_not_creation_time() {
+        let store = store();
+        let old = campaign(Utc::now() - TimeDelta::days(60), CampaignStatus::Sending);
+        let id = old.id.clone();
+        store.insert_campaign(old, Vec::new());
+
+        // Held for weeks and only just completed
+        store.set_campaign_status(&id, CampaignStatus::Completed);
+        assert_eq!(store.prune(), 0);
+        assert!(store.campaign(&id).unwrap().digest.is_some());
+
+        store.update(|data| {
+            data.campaigns.get_mut(&id).unwrap().finished_at =
+                Some(Utc::now() - PRUNE_AFTER - TimeDelta::minutes(1));
+        });
+        assert_eq!(store.prune(), 1);
+        assert!(store.campaign(&id).unwrap().digest.is_none());
+        assert_eq!(store.prune(), 0);
+    }
+
+    #[test]
+    fn clears_finish_time_when_reopened() {
+        let store = store();
+        let campaign = campaign(Utc::now(), CampaignStatus::Sending);
+        let id = campaign.id.clone();
+        store.insert_campaign(campaign, Vec::new());
+
+        store.set_campaign_status(&id, CampaignStatus::Cancelled);
+        assert!(store.campaign(&id).unwrap().finished_at.is_some());
+        store.set_campaign_status(&id, CampaignStatus::Queued);
+        assert!(store.campaign(&id).unwrap().finished_at.is_none());
+    }
+
+    #[test]
+    fn keeps_finished_campaigns_without_a_finish_time_for_the_full_period() {
+        let store = store();
+        let legacy = campaign(Utc::now() - TimeDelta::days(60), CampaignStatus::Completed);
+        let id = legacy.id.clone();
+        store.insert_campaign(legacy, Vec::new());
+
+        assert_eq!(store.prune(), 0);
+        let campaign = store.campaign(&id).unwrap();
+        assert!(campaign.digest.is_some());
+        assert!(campaign.finished_at.is_some());
+    }
+}
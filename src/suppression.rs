@@ -0,0 +1,447 @@
+use crate::{
+    config::Config,
+    ghost,
+    store::{SendRecord, Store},
+};
+use chrono::{DateTime, Utc};
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuppressionReason {
+    Bounced,
+    Complained,
+    Manual,
+    Imported,
+}
+
+/// What a suppression entry matches against.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuppressionKind {
+    /// A single email address
+    #[default]
+    Address,
+    /// Every address at a domain, not including its subdomains
+    Domain,
+    /// Addresses matching a regular expression (case-insensitive)
+    Regex,
+}
+
+impl SuppressionKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SuppressionKind::Address => "address",
+            SuppressionKind::Domain => "domain",
+            SuppressionKind::Regex => "regex",
+        }
+    }
+}
+
+impl std::str::FromStr for SuppressionKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "address" | "email" => Ok(SuppressionKind::Address),
+            "domain" => Ok(SuppressionKind::Domain),
+            "regex" => Ok(SuppressionKind::Regex),
+            other => Err(anyhow::anyhow!("Unknown suppression type '{}'", other)),
+        }
+    }
+}
+
+/// An address, domain or pattern that must never be emailed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suppression {
+    #[serde(default)]
+    pub kind: SuppressionKind,
+    #[serde(alias = "email")]
+    pub value: String,
+    pub reason: SuppressionReason,
+    pub detail: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Suppression {
+    /// Builds an entry, normalizing the value and rejecting invalid patterns.
+    pub fn new(
+        kind: SuppressionKind,
+        value: &str,
+        reason: SuppressionReason,
+        detail: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let value = match kind {
+            SuppressionKind::Address => {
+                let value = value.trim().to_lowercase();
+                if !value.contains('@') {
+                    return Err(anyhow::anyhow!("'{}' is not an email address", value));
+                }
+                value
+            }
+            SuppressionKind::Domain => value.trim().trim_start_matches('@').to_lowercase(),
+            SuppressionKind::Regex => {
+                Regex::new(&pattern(value))
+                    .map_err(|e| anyhow::anyhow!("Invalid suppression regex: {}", e))?;
+                value.to_string()
+            }
+        };
+
+        if value.is_empty() {
+            return Err(anyhow::anyhow!("Suppression value is empty"));
+        }
+
+        Ok(Suppression {
+            kind,
+            value,
+            reason,
+            detail,
+            created_at: Utc::now(),
+        })
+    }
+
+    /// Key of the entry in the store, unique per kind and value.
+    pub fn key(&self) -> String {
+        Self::key_for(self.kind, &self.value)
+    }
+
+    pub fn key_for(kind: SuppressionKind, value: &str) -> String {
+        match kind {
+            SuppressionKind::Regex => format!("regex:{}", value),
+            _ => format!("{}:{}", kind.as_str(), value.trim().to_lowercase()),
+        }
+    }
+}
+
+/// Checks addresses against the whole suppression list. Built once per send so
+/// regexes are compiled once rather than for every recipient.
+pub struct SuppressionMatcher {
+    addresses: HashSet<String>,
+    domains: HashSet<String>,
+    patterns: RegexSet,
+}
+
+/// Regex suppressions match regardless of case, like addresses and domains.
+fn pattern(value: &str) -> String {
+    format!("(?i){}", value)
+}
+
+impl SuppressionMatcher {
+    /// Fails if the regexes can't be compiled together, e.g. because there are
+    /// too many of them, rather than letting suppressed addresses through.
+    pub fn new<'a>(
+        suppressions: impl IntoIterator<Item = &'a Suppression>,
+    ) -> anyhow::Result<Self> {
+        let mut addresses = HashSet::new();
+        let mut domains = HashSet::new();
+        let mut patterns = Vec::new();
+
+        for suppression in suppressions {
+            match suppression.kind {
+                SuppressionKind::Address => {
+                    addresses.insert(suppression.value.to_lowercase());
+                }
+                SuppressionKind::Domain => {
+                    domains.insert(suppression.value.to_lowercase());
+                }
+                SuppressionKind::Regex => patterns.push(pattern(&suppression.value)),
+            }
+        }
+
+        let patterns = RegexSet::new(&patterns)
+            .map_err(|e| anyhow::anyhow!("Failed to compile suppression regexes: {}", e))?;
+
+        Ok(SuppressionMatcher {
+            addresses,
+            domains,
+            patterns,
+        })
+    }
+
+    pub fn is_suppressed(&self, email: &str) -> bool {
+        let email = email.trim().to_lowercase();
+        let domain = email.rsplit_once('@').map_or("", |(_, domain)| domain);
+
+        self.addresses.contains(&email)
+            || self.domains.contains(domain)
+            || self.patterns.is_match(&email)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvRow {
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
+    #[serde(alias = "email", alias = "Email", alias = "email_address")]
+    value: String,
+    #[serde(default)]
+    reason: Option<SuppressionReason>,
+    #[serde(default)]
+    detail: Option<String>,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+}
+
+/// Exports the suppression list as CSV with `type,value,reason,detail,created_at`
+/// columns.
+pub fn export_csv(suppressions: &[Suppression]) -> anyhow::Result<String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for suppression in suppressions {
+        writer.serialize(CsvRow {
+            kind: Some(suppression.kind.as_str().to_string()),
+            value: suppression.value.clone(),
+            reason: Some(suppression.reason),
+            detail: suppression.detail.clone(),
+            created_at: Some(suppression.created_at),
+        })?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Parses a suppression CSV. Besides this service's own export format, a file with
+/// just an `email` column (as exported by most providers) is accepted, and rows
+/// without a type are treated as addresses.
+pub fn import_csv(data: &[u8]) -> anyhow::Result<Vec<Suppression>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(data);
+
+    let mut suppressions = Vec::new();
+    for (index, row) in reader.deserialize::<CsvRow>().enumerate() {
+        // Header is line 1
+        let line = index + 2;
+        let row = row.map_err(|e| anyhow::anyhow!("Invalid CSV on line {}: {}", line, e))?;
+        let kind: SuppressionKind = row.kind.as_deref().unwrap_or_default().parse()?;
+
+        let mut suppression = Suppression::new(
+            kind,
+            &row.value,
+            row.reason.unwrap_or(SuppressionReason::Imported),
+            row.detail.filter(|d| !d.is_empty()),
+        )
+        .map_err(|e| anyhow::anyhow!("Line {}: {}", line, e))?;
+        if let Some(created_at) = row.created_at {
+            suppression.created_at = created_at;
+        }
+        suppressions.push(suppression);
+    }
+
+    Ok(suppressions)
+}
+
+/// What to change in Ghost when a member is suppressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GhostAction {
+    /// Set the member's `email_disabled` flag
+    EmailDisabled,
+    /// Apply the `SUPPRESSION_LABEL` label
+    Label,
+    /// Unsubscribe the member from all newsletters
+    Unsubscribe,
+}
+
+impl std::str::FromStr for GhostAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "email_disabled" => Ok(GhostAction::EmailDisabled),
+            "label" => Ok(GhostAction::Label),
+            "unsubscribe" => Ok(GhostAction::Unsubscribe),
+            other => Err(anyhow::anyhow!(
+                "Unknown Ghost suppression action '{}'",
+                other
+            )),
+        }
+    }
+}
+
+/// Suppresses the recipient of a bounced or complained send, and applies the
+/// configured Ghost actions to the member.
+pub async fn suppress_member(
+    config: &Config,
+    store: &Store,
+    send: &SendRecord,
+    reason: SuppressionReason,
+    detail: Option<String>,
+) {
+    let suppression = match Suppression::new(SuppressionKind::Address, &send.email, reason, detail)
+    {
+        Ok(suppression) => suppression,
+        Err(e) => {
+            tracing::error!("Failed to suppress {}: {}", send.email, e);
+            return;
+        }
+    };
+
+    let added = match store.suppress(suppression) {
+        Ok(added) => added,
+        Err(e) => {
+            tracing::error!("Failed to suppress {}: {}", send.email, e);
+            return;
+        }
+    };
+
+    if !added {
+        tracing::debug!("{} is already suppressed", send.email);
+        return;
+    }
+
+    tracing::info!("Suppressed {} ({:?})", send.email, reason);
+    match store.skip_suppressed_recipients() {
+        Ok(0) => {}
+        Ok(skipped) => tracing::info!("Skipped {} pending emails to {}", skipped, send.email),
+        Err(e) => tracing::error!("Failed to skip pending emails to {}: {}", send.email, e),
+    }
+
+    for action in &config.ghost_suppression_actions {
+        let result = match action {
+            GhostAction::EmailDisabled => ghost::update_member(
+                config,
+                &send.member_id,
+                serde_json::json!({ "email_disabled": true }),
+            )
+            .await
+            .map(|_| ()),
+            GhostAction::Label => {
+                ghost::add_member_label(config, &send.member_id, &config.suppression_label).await
+            }
+            GhostAction::Unsubscribe => ghost::unsubscribe_member(config, &send.member_id).await,
+        };
+
+        if let Err(e) = result {
+            tracing::error!(
+                "Failed to apply {:?} to Ghost member {}: {}",
+                action,
+                send.member_id,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suppression(kind: SuppressionKind, value: &str) -> Suppression {
+        Suppression::new(kind, value, SuppressionReason::Manual, None).unwrap()
+    }
+
+    fn matcher(suppressions: &[Suppression]) -> SuppressionMatcher {
+        SuppressionMatcher::new(suppressions).unwrap()
+    }
+
+    #[test]
+    fn matches_addresses_regardless_of_case() {
+        let matcher = matcher(&[suppression(SuppressionKind::Address, " Jamie@Example.com ")]);
+        assert!(matcher.is_suppressed("jamie@example.com"));
+        assert!(matcher.is_suppressed("JAMIE@EXAMPLE.COM "));
+        assert!(!matcher.is_suppressed("sam@example.com"));
+    }
+
+    #[test]
+    fn matches_domains_exactly() {
+        let matcher = matcher(&[suppression(SuppressionKind::Domain, "@Example.com")]);
+        assert!(matcher.is_suppressed("user@example.com"));
+        assert!(matcher.is_suppressed("User@EXAMPLE.COM"));
+        assert!(!matcher.is_suppressed("user@sub.example.com"));
+        assert!(!matcher.is_suppressed("user@notexample.com"));
+        assert!(!matcher.is_suppressed("example.com"));
+    }
+
+    #[test]
+    fn matches_regexes_regardless_of_case() {
+        let matcher = matcher(&[suppression(SuppressionKind::Regex, r"^test\+.*@")]);
+        assert!(matcher.is_suppressed("test+1@example.com"));
+        assert!(matcher.is_suppressed("TEST+1@example.com"));
+        assert!(!matcher.is_suppressed("test@example.com"));
+    }
+
+    #[test]
+    fn rejects_invalid_regexes() {
+        assert!(Suppression::new(
+            SuppressionKind::Regex,
+            "(unclosed",
+            SuppressionReason::Manual,
+            None
+        )
+        .is_err());
+        assert!(Suppression::new(
+            SuppressionKind::Address,
+            "no-at-sign",
+            SuppressionReason::Manual,
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn fails_closed_when_regexes_dont_compile() {
+        // Entries loaded from the store skip validation in `Suppression::new`
+        let mut invalid = suppression(SuppressionKind::Regex, "valid");
+        invalid.value = "(unclosed".to_string();
+        assert!(SuppressionMatcher::new(&[invalid]).is_err());
+
+        // Each compiles alone, but together they exceed the size limit
+        let huge = suppression(SuppressionKind::Regex, r"[a-z]{1000}");
+        assert!(SuppressionMatcher::new(std::iter::repeat(&huge).take(50)).is_err());
+    }
+
+    #[test]
+    fn export_round_trips_through_import() {
+        let suppressions = vec![
+            suppression(SuppressionKind::Address, "jamie@example.com"),
+            Suppression::new(
+                SuppressionKind::Domain,
+                "example.org",
+                SuppressionReason::Bounced,
+                Some("Mailbox full, \"permanently\"".to_string()),
+            )
+            .unwrap(),
+            suppression(SuppressionKind::Regex, r"^test\+.*@(a|b)\.com$"),
+        ];
+
+        let csv = export_csv(&suppressions).unwrap();
+        let imported = import_csv(csv.as_bytes()).unwrap();
+
+        assert_eq!(imported.len(), suppressions.len());
+        for (imported, original) in imported.iter().zip(&suppressions) {
+            assert_eq!(imported.kind, original.kind);
+            assert_eq!(imported.value, original.value);
+            assert_eq!(imported.reason, original.reason);
+            assert_eq!(imported.detail, original.detail);
+            assert_eq!(imported.created_at, original.created_at);
+        }
+        assert_eq!(export_csv(&imported).unwrap(), csv);
+    }
+
+    #[test]
+    fn imports_a_single_email_column() {
+        let imported = import_csv(b"Email\nJamie@Example.com\nsam@example.com\n").unwrap();
+        let values: Vec<&str> = imported.iter().map(|s| s.value.as_str()).collect();
+        assert_eq!(values, ["jamie@example.com", "sam@example.com"]);
+        assert!(
+            imported
+                .iter()
+                .all(|s| s.kind == SuppressionKind::Address
+                    && s.reason == SuppressionReason::Imported)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_rows() {
+        let error = |csv: &str| import_csv(csv.as_bytes()).unwrap_err().to_string();
+
+        assert!(error("type,value\naddress,a@example.com\nphone,555\n").contains("phone"));
+        assert!(
+            error("type,value\naddress,a@example.com\naddress,not-an-email\n").contains("Line 3")
+        );
+        assert!(error("type,value\nregex,(unclosed\n").contains("Line 2"));
+        assert!(error("type,value,reason\naddress,a@example.com,lost\n").contains("line 2"));
+        assert!(import_csv(b"type,value\naddress,\n").is_err());
+    }
+}
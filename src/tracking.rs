@@ -0,0 +1,306 @@
+use crate::{
+    config::Config,
+    store::{Click, Open, OpenKind, Store},
+};
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{TimeDelta, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+
+// Smallest transparent GIF
+const PIXEL_GIF: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+// Truncated HMAC, long enough that tokens can't be guessed while keeping URLs short
+const SIGNATURE_LEN: usize = 16;
+
+// Opens this soon after sending are scanners fetching everything on delivery
+const SCANNER_WINDOW: TimeDelta = TimeDelta::seconds(5);
+
+// User agent fragments of security scanners and link previewers
+const BOT_USER_AGENTS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "preview",
+    "scanner",
+    "barracuda",
+    "mimecast",
+    "proofpoint",
+    "python-requests",
+    "curl/",
+];
+
+/// `<img>` tag of the open tracking pixel for a member of a campaign.
+pub fn open_pixel(config: &Config, campaign_id: &str, member_uuid: &str) -> String {
+    let token = sign_token(
+        &config.signing_secret,
+        &format!("o:{}:{}", campaign_id, member_uuid),
+    );
+    format!(
+        r#"<img src="{}/track/open/{}" width="1" height="1" alt="" style="display:block;width:1px;height:1px;border:0;">"#,
+        config.public_url.as_deref().unwrap_or_default(),
+        token
+    )
+}
+
+/// Click tracking redirect for a link, as found in an `href` attribute.
+pub fn click_link(config: &Config, campaign_id: &str, member_uuid: &str, href: &str) -> String {
+    // The target is signed as the browser would follow it, with entities decoded
+    let target = href.trim().replace("&amp;", "&");
+    let token = sign_token(
+        &config.signing_secret,
+        &format!("c:{}:{}:{}", campaign_id, member_uuid, target),
+    );
+    format!(
+        "{}/c/r/{}",
+        config.public_url.as_deref().unwrap_or_default(),
+        token
+    )
+}
+
+/// Encodes `payload` as `{payload}.{signature}`, both base64url.
+fn sign_token(secret: &str, payload: &str) -> String {
+    format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(payload),
+        URL_SAFE_NO_PAD.encode(signature(secret, payload.as_bytes()))
+    )
+}
+
+/// Returns the payload of a token signed by `sign_token`, if the signature matches.
+fn verify_token(secret: &str, token: &str) -> Option<String> {
+    let (payload, signature) = token.split_once('.')?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    if signature.len() != SIGNATURE_LEN {
+        return None;
+    }
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(&payload);
+    mac.verify_truncated_left(&signature).ok()?;
+
+    String::from_utf8(payload).ok()
+}
+
+fn signature(secret: &str, payload: &[u8]) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload);
+    mac.finalize().into_bytes()[..SIGNATURE_LEN].to_vec()
+}
+
+/// Serves the tracking pixel and records the open. The pixel is returned even
+/// for invalid tokens so a broken image never shows up in the email.
+pub async fn handle_open(
+    State(config): State<Config>,
+    State(store): State<Store>,
+    Path(token): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let pixel = (
+        [
+            (header::CONTENT_TYPE, "image/gif"),
+            (
+                header::CACHE_CONTROL,
+                "no-store, no-cache, must-revalidate, private",
+            ),
+        ],
+        PIXEL_GIF,
+    )
+        .into_response();
+
+    let Some(payload) = verify_token(&config.signing_secret, &token) else {
+        tracing::warn!("Invalid open tracking token");
+        return pixel;
+    };
+    let Some((campaign_id, member_uuid)) = payload
+        .strip_prefix("o:")
+        .and_then(|ids| ids.split_once(':'))
+    else {
+        tracing::warn!("Malformed open tracking payload: {}", payload);
+        return pixel;
+    };
+
+    let Some(send) = store.find_send(campaign_id, member_uuid) else {
+        tracing::debug!(
+            "Open for unknown send of campaign {} to {}",
+            campaign_id,
+            member_uuid
+        );
+        return pixel;
+    };
+
+    let now = Utc::now();
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let kind = classify_open(
+        user_agent,
+        client_ip(&headers, addr, config.trust_proxy),
+        now - send.sent_at,
+    );
+
+    if store.record_open(&send.email_id, Open { kind, at: now }) {
+        tracing::info!(
+            "Recorded {:?} open for {} (campaign {})",
+            kind,
+            send.email,
+            campaign_id
+        );
+    }
+
+    pixel
+}
+
+/// Records a click on a tracked link and redirects to its target.
+pub async fn handle_click(
+    State(config): State<Config>,
+    State(store): State<Store>,
+    Path(token): Path<String>,
+) -> Result<Redirect, StatusCode> {
+    let payload = verify_token(&config.signing_secret, &token).ok_or_else(|| {
+        tracing::warn!("Invalid click tracking token");
+        StatusCode::NOT_FOUND
+    })?;
+
+    let mut parts = payload.splitn(4, ':');
+    let (Some("c"), Some(campaign_id), Some(member_uuid), Some(url)) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        tracing::warn!("Malformed click tracking payload: {}", payload);
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    // Always send the reader on, even when the click can't be attributed
+    let click = Click {
+        url: url.to_string(),
+        at: Utc::now(),
+    };
+    match store.record_click(campaign_id, member_uuid, click) {
+        Some(email) => tracing::info!(
+            "Recorded click on {} by {} (campaign {})",
+            url,
+            email,
+            campaign_id
+        ),
+        None => tracing::debug!(
+            "Click for unknown send of campaign {} to {}",
+            campaign_id,
+            member_uuid
+        ),
+    }
+
+    Ok(Redirect::to(url))
+}
+
+/// The client address, taken from `X-Forwarded-For` with `TRUST_PROXY` on.
+/// Clients can send the header themselves, so only the address the proxy
+/// appended last counts.
+fn client_ip(headers: &HeaderMap, addr: SocketAddr, trust_proxy: bool) -> IpAddr {
+    headers
+        .get("x-forwarded-for")
+        .filter(|_| trust_proxy)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .unwrap_or_else(|| addr.ip())
+}
+
+/// Guesses who fetched the pixel.
+///
+/// Gmail always fetches images through its proxy, but only when the message is
+/// opened. Apple Mail Privacy Protection prefetches every image on delivery with a
+/// bare `Mozilla/5.0` user agent, often from Apple's 17.0.0.0/8 network.
+fn classify_open(user_agent: &str, ip: IpAddr, since_sent: TimeDelta) -> OpenKind {
+    let user_agent_lower = user_agent.to_lowercase();
+
+    if user_agent.contains("GoogleImageProxy") {
+        OpenKind::GmailProxy
+    } else if user_agent.trim() == "Mozilla/5.0" || is_apple_network(ip) {
+        OpenKind::AppleMpp
+    } else if user_agent.trim().is_empty()
+        || since_sent < SCANNER_WINDOW
+        || BOT_USER_AGENTS
+            .iter()
+            .any(|bot| user_agent_lower.contains(bot))
+    {
+        OpenKind::Bot
+    } else {
+        OpenKind::Direct
+    }
+}
+
+fn is_apple_network(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.octets()[0] == 17,
+        IpAddr::V6(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "signing-secret";
+
+    #[test]
+    fn verifies_own_tokens() {
+        let token = sign_token(SECRET, "c:campaign:uuid:https://example.com/?a=1&b=2");
+        assert_eq!(
+            verify_token(SECRET, &token).as_deref(),
+            Some("c:campaign:uuid:https://example.com/?a=1&b=2")
+        );
+    }
+
+    #[test]
+    fn rejects_other_secret() {
+        let token = sign_token(SECRET, "o:campaign:uuid");
+        assert_eq!(verify_token("other-secret", &token), None);
+    }
+
+    #[test]
+    fn rejects_tampered_payload() {
+        let token = sign_token(SECRET, "c:campaign:uuid:https://example.com/");
+        let (_, signature) = token.split_once('.').unwrap();
+        let payload = URL_SAFE_NO_PAD.encode("c:campaign:uuid:https://evil.example/");
+        assert_eq!(
+            verify_token(SECRET, &format!("{}.{}", payload, signature)),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_signature_of_another_member() {
+        let token = sign_token(SECRET, "o:campaign:uuid-1");
+        let other = sign_token(SECRET, "o:campaign:uuid-2");
+        let (payload, _) = token.split_once('.').unwrap();
+        let (_, signature) = other.split_once('.').unwrap();
+        assert_eq!(
+            verify_token(SECRET, &format!("{}.{}", payload, signature)),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_or_malformed_tokens() {
+        let token = sign_token(SECRET, "o:campaign:uuid");
+        assert_eq!(verify_token(SECRET, &token[..token.len() - 2]), None);
+        assert_eq!(verify_token(SECRET, "no-signature"), None);
+        assert_eq!(verify_token(SECRET, "!!!.!!!"), None);
+        assert_eq!(verify_token(SECRET, ""), None);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{config::Config, ghost, page::page, store::Store};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Html,
+};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+const STYLE: &str = "body { max-width: 480px; margin-top: 80px; text-align: center; }";
+
+#[derive(Debug, Deserialize)]
+pub struct UnsubscribeParams {
+    pub uuid: String,
+    pub key: String,
+}
+
+/// Builds the unsubscribe link for a member.
+///
+/// When `PUBLIC_URL` is configured the link points at this service's one-click
+/// endpoint, otherwise it falls back to Ghost's own unsubscribe route, signed with
+/// Ghost's members secret when one is available.
+pub fn unsubscribe_link(config: &Config, member_uuid: &str) -> String {
+    match &config.public_url {
+        Some(public_url) => format!(
+            "{}/unsubscribe?uuid={}&key={}",
+            public_url,
+            member_uuid,
+            sign(&config.signing_secret, member_uuid)
+        ),
+        None => {
+            let mut link = format!("{}/unsubscribe/?uuid={}", config.ghost_url, member_uuid);
+            if let Some(secret) = &config.ghost_members_secret {
+                link.push_str(&format!("&key={}", sign(secret, member_uuid)));
+            }
+            link
+        }
+    }
+}
+
+/// RFC 2369/8058 headers that let mail clients offer an unsubscribe button.
+///
+/// `List-Unsubscribe-Post` is only added when the link points at this service,
+/// since Ghost's own unsubscribe route doesn't accept one-click POSTs.
+pub fn list_unsubscribe_headers(config: &Config, member_uuid: &str) -> Vec<(&'static str, String)> {
+    let mut targets = Vec::new();
+    if let Some(mailto) = &config.list_unsubscribe_mailto {
+        targets.push(format!("<mailto:{}?subject=unsubscribe>", mailto));
+    }
+    targets.push(format!("<{}>", unsubscribe_link(config, member_uuid)));
+
+    let mut headers = vec![("List-Unsubscribe", targets.join(", "))];
+    if config.public_url.is_some() {
+        headers.push((
+            "List-Unsubscribe-Post",
+            "List-Unsubscribe=One-Click".to_string(),
+        ));
+    }
+    headers
+}
+
+/// Hex-encoded HMAC-SHA256 of the member uuid, the same scheme Ghost uses for its
+/// unsubscribe `key` parameter.
+fn sign(secret: &str, member_uuid: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(member_uuid.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn verify(secret: &str, member_uuid: &str, key: &str) -> bool {
+    let Ok(key) = hex::decode(key) else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(member_uuid.as_bytes());
+    mac.verify_slice(&key).is_ok()
+}
+
+/// Shows a confirmation page. Unsubscribing only happens on POST so link scanners
+/// and prefetchers can't unsubscribe members by following the link.
+pub async fn unsubscribe_page(
+    State(config): State<Config>,
+    Query(params): Query<UnsubscribeParams>,
+) -> Result<Html<String>, StatusCode> {
+    if !verify(&config.signing_secret, &params.uuid, &params.key) {
+        tracing::warn!("Invalid unsubscribe key for member {}", params.uuid);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(Html(page(
+        "Unsubscribe",
+        STYLE,
+        &format!(
+            r#"<p>Click below to stop receiving emails.</p>
+            <form method="post" action="/unsubscribe?uuid={}&amp;key={}">
+                <button type="submit">Unsubscribe</button>
+            </form>"#,
+            params.uuid, params.key
+        ),
+    )))
+}
+
+/// One-click unsubscribe: verifies the signed link and unsubscribes the member
+/// from all newsletters through the Ghost Admin API.
+pub async fn handle_unsubscribe(
+    State(config): State<Config>,
+    State(store): State<Store>,
+    Query(params): Query<UnsubscribeParams>,
+) -> Result<Html<String>, StatusCode> {
+    if !verify(&config.signing_secret, &params.uuid, &params.key) {
+        tracing::warn!("Invalid unsubscribe key for member {}", params.uuid);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let member = ghost::fetch_member_by_uuid(&config, &params.uuid)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to look up member {}: {}", params.uuid, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or_else(|| {
+            tracing::warn!("Unsubscribe requested for unknown member {}", params.uuid);
+            StatusCode::NOT_FOUND
+        })?;
+
+    ghost::unsubscribe_member(&config, &member.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to unsubscribe member {}: {}", member.id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Welcome emails and other campaigns still waiting for them are dropped too
+    let skipped = store.skip_pending_recipients(&member.id, "unsubscribed");
+    tracing::info!(
+        "Member {} unsubscribed, skipped {} pending emails",
+        params.uuid,
+        skipped
+    );
+
+    Ok(Html(page(
+        "Unsubscribed",
+        STYLE,
+        "<p>You have been unsubscribed and won't receive any more emails.</p>",
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "signing-secret";
+    const UUID: &str = "6b1e0c1e-5f4a-4c8e-9a57-3f0e2d7c9b10";
+
+    #[test]
+    fn verifies_own_keys() {
+        assert!(verify(SECRET, UUID, &sign(SECRET, UUID)));
+    }
+
+    #[test]
+    fn rejects_key_of_another_member() {
+        let key = sign(SECRET, "00000000-0000-0000-0000-000000000000");
+        assert!(!verify(SECRET, UUID, &key));
+    }
+
+    #[test]
+    fn rejects_other_secret() {
+        assert!(!verify(SECRET, UUID, &sign("ghost-members-secret", UUID)));
+    }
+
+    #[test]
+    fn rejects_tampered_or_malformed_keys() {
+        let mut key = sign(SECRET, UUID);
+        let last = if key.ends_with('0') { "1" } else { "0" };
+        key.replace_range(key.len() - 1.., last);
+        assert!(!verify(SECRET, UUID, &key));
+
+        let key = sign(SECRET, UUID);
+        assert!(!verify(SECRET, UUID, &key[..key.len() - 2]));
+        assert!(!verify(SECRET, UUID, "not hex"));
+        assert!(!verify(SECRET, UUID, ""));
+    }
+}
@@ -1,58 +1,29 @@
-use crate::ghost;
 use crate::{
     config::Config,
-    email::format_email,
-    ghost::{fetch_subscribers, WebhookPayload},
+    ghost::WebhookPayload,
+    queue::{Queue, SendOptions},
+    store::Store,
 };
 use axum::{
-    body::{to_bytes, Body},
+    body::{to_bytes, Body, Bytes},
     extract::State,
     http::{Request, StatusCode},
 };
 use hmac::{Hmac, Mac};
-use resend_rs::{types::CreateEmailBaseOptions, Resend};
 use sha2::Sha256;
-use std::time::Duration;
 
 // Define a reasonable size limit for webhook payloads (e.g., 5MB)
 const MAX_BODY_SIZE: usize = 5 * 1024 * 1024;
 
-// Constants for batch processing
-const BATCH_SIZE: usize = 100; // Resend's max batch size
-const BATCH_DELAY: Duration = Duration::from_secs(1);
-
 pub async fn handle_webhook(
     State(config): State<Config>,
+    State(store): State<Store>,
+    State(queue): State<Queue>,
     request: Request<Body>,
 ) -> Result<StatusCode, StatusCode> {
     tracing::info!("Received webhook request");
 
-    // Extract and clone the signature header before consuming the body
-    let signature_header = request
-        .headers()
-        .get("x-ghost-signature")
-        .ok_or_else(|| {
-            tracing::warn!("Missing x-ghost-signature header");
-            StatusCode::UNAUTHORIZED
-        })?
-        .to_str()
-        .map_err(|_| {
-            tracing::error!("Invalid signature format in header");
-            StatusCode::BAD_REQUEST
-        })?
-        .to_owned();
-
-    tracing::debug!("Received signature header: {}", signature_header);
-
-    // Now we can safely consume the request body
-    tracing::debug!("Reading request body...");
-    let body_bytes = to_bytes(request.into_body(), MAX_BODY_SIZE)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to read request body: {}", e);
-            StatusCode::BAD_REQUEST
-        })?;
-    tracing::debug!("Received body of {} bytes", body_bytes.len());
+    let body_bytes = verified_body(&config, request).await?;
 
     // Log the raw JSON for debugging
     let body_string = String::from_utf8_lossy(&body_bytes);
@@ -93,6 +64,65 @@ pub async fn handle_webhook(
         payload.post.current.id
     );
 
+    // Ghost fires the webhook again when a post is unpublished and republished
+    if let Some(campaign) = store.campaign_for_post(&payload.post.current.id, config.dry_run) {
+        tracing::info!(
+            "Post {} was already sent as campaign {}, skipping",
+            payload.post.current.id,
+            campaign.id
+        );
+        return Ok(StatusCode::OK);
+    }
+
+    // Sending happens in the background so Ghost doesn't time out on large lists
+    queue
+        .enqueue_post(
+            payload.post.current,
+            SendOptions {
+                dry_run: config.dry_run,
+                ..SendOptions::default()
+            },
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to queue campaign: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    tracing::info!("Webhook processing completed successfully");
+    Ok(StatusCode::OK)
+}
+
+/// Reads the body of a Ghost webhook and checks its `x-ghost-signature` against
+/// `WEBHOOK_SECRET`, so every Ghost event is verified the same way.
+pub async fn verified_body(config: &Config, request: Request<Body>) -> Result<Bytes, StatusCode> {
+    // Extract and clone the signature header before consuming the body
+    let signature_header = request
+        .headers()
+        .get("x-ghost-signature")
+        .ok_or_else(|| {
+            tracing::warn!("Missing x-ghost-signature header");
+            StatusCode::UNAUTHORIZED
+        })?
+        .to_str()
+        .map_err(|_| {
+            tracing::error!("Invalid signature format in header");
+            StatusCode::BAD_REQUEST
+        })?
+        .to_owned();
+
+    tracing::debug!("Received signature header: {}", signature_header);
+
+    // Now we can safely consume the request body
+    tracing::debug!("Reading request body...");
+    let body_bytes = to_bytes(request.into_body(), MAX_BODY_SIZE)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to read request body: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+    tracing::debug!("Received body of {} bytes", body_bytes.len());
+
     // Extract both signature and timestamp
     tracing::debug!("Parsing signature header parts...");
     let parts: Vec<&str> = signature_header.split(", ").collect();
@@ -176,75 +206,5 @@ pub async fn handle_webhook(
 
     tracing::debug!("Webhook signature verified successfully");
 
-    // Fetch settings once before processing emails
-    let settings = ghost::fetch_settings(&config).await.map_err(|e| {
-        tracing::error!("Failed to fetch Ghost settings: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-
-    // Fetch subscribers
-    let subscribers = fetch_subscribers(&config).await.map_err(|e| {
-        tracing::error!("Failed to fetch subscribers: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-
-    // Send emails using Resend in batches
-    let resend_client = Resend::new(&config.resend_api_key);
-
-    for (batch_index, subscriber_batch) in subscribers.chunks(BATCH_SIZE).enumerate() {
-        tracing::info!(
-            "Processing batch {} with {} subscribers",
-            batch_index + 1,
-            subscriber_batch.len()
-        );
-
-        let mut batch_emails = Vec::new();
-
-        // Prepare all emails in this batch
-        for subscriber in subscriber_batch {
-            tracing::debug!("Preparing email for subscriber: {}", subscriber.email);
-
-            let html_content = format_email(&payload.post.current, subscriber, &config, &settings)
-                .await
-                .map_err(|e| {
-                    tracing::error!("Failed to format email: {}", e);
-                    StatusCode::INTERNAL_SERVER_ERROR
-                })?;
-
-            let email = CreateEmailBaseOptions::new(
-                &config.from_email,
-                vec![subscriber.email.clone()],
-                payload.post.current.title.clone(),
-            )
-            .with_html(&html_content);
-
-            batch_emails.push(email);
-        }
-
-        // Send the entire batch using BatchSvc
-        match resend_client.batch.send(batch_emails).await {
-            Ok(responses) => {
-                tracing::info!(
-                    "Successfully sent batch {} ({} emails)",
-                    batch_index + 1,
-                    responses.len()
-                );
-                for response in responses {
-                    tracing::debug!("Email sent with ID: {}", response.id);
-                }
-            }
-            Err(e) => {
-                tracing::error!("Failed to send batch {}: {}", batch_index + 1, e);
-            }
-        }
-
-        // Sleep between batches to respect rate limits
-        if batch_index < subscribers.chunks(BATCH_SIZE).len() - 1 {
-            tracing::debug!("Sleeping for 1 second before next batch");
-            tokio::time::sleep(BATCH_DELAY).await;
-        }
-    }
-
-    tracing::info!("Webhook processing completed successfully");
-    Ok(StatusCode::OK)
+    Ok(body_bytes)
 }
@@ -0,0 +1,161 @@
+use crate::{config::Config, ghost::MemberWebhookPayload, queue::Queue, webhook};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+};
+use std::time::Duration;
+
+/// One email of the welcome sequence: a Ghost post, drafts included, sent this
+/// long after a member signs up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WelcomeStep {
+    pub delay: Duration,
+    pub post_id: String,
+}
+
+impl std::str::FromStr for WelcomeStep {
+    type Err = anyhow::Error;
+
+    /// Reads `post_id` to send right away, or `3d:post_id` to wait, with the
+    /// delay in minutes (`m`), hours (`h`) or days (`d`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (delay, post_id) = match s.trim().split_once(':') {
+            Some((delay, post_id)) => (parse_delay(delay.trim())?, post_id.trim()),
+            None => (Duration::ZERO, s.trim()),
+        };
+        if post_id.is_empty() {
+            return Err(anyhow::anyhow!("Welcome email '{}' has no post ID", s));
+        }
+
+        Ok(WelcomeStep {
+            delay,
+            post_id: post_id.to_string(),
+        })
+    }
+}
+
+fn parse_delay(delay: &str) -> anyhow::Result<Duration> {
+    let invalid = || anyhow::anyhow!("Invalid welcome email delay '{}'", delay);
+    // The unit is the last character, which isn't always a single byte
+    let (split, _) = delay.char_indices().last().ok_or_else(invalid)?;
+    let (amount, unit) = delay.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+
+    let seconds_per_unit = match unit {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(invalid()),
+    };
+    let seconds = amount.checked_mul(seconds_per_unit).ok_or_else(invalid)?;
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Starts the welcome sequence for a member Ghost reports as added. The same
+/// `WEBHOOK_SECRET` signs member events as post events.
+pub async fn handle_member_webhook(
+    State(config): State<Config>,
+    State(queue): State<Queue>,
+    request: Request<Body>,
+) -> Result<StatusCode, StatusCode> {
+    tracing::info!("Received member webhook request");
+
+    let body_bytes = webhook::verified_body(&config, request).await?;
+    let payload: MemberWebhookPayload = serde_json::from_slice(&body_bytes).map_err(|e| {
+        tracing::error!("Failed to parse member webhook body: {}", e);
+        StatusCode::BAD_REQUEST
+    })?;
+    let member = payload.member.current;
+
+    if config.welcome_emails.is_empty() {
+        tracing::info!(
+            "No welcome emails configured, ignoring member {}",
+            member.id
+        );
+        return Ok(StatusCode::OK);
+    }
+
+    // Members can sign up without subscribing to any newsletter
+    if !member.is_subscribed() {
+        tracing::info!(
+            "Member {} isn't subscribed to emails, not welcoming them",
+            member.id
+        );
+        return Ok(StatusCode::OK);
+    }
+
+    // Sending happens in the background, delayed steps wait in the queue
+    queue.enqueue_welcome(&member).await.map_err(|e| {
+        tracing::error!("Failed to queue welcome emails for {}: {}", member.id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(s: &str) -> anyhow::Result<WelcomeStep> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_steps_without_a_delay() {
+        assert_eq!(
+            step(" 65a1b2c3 ").unwrap(),
+            WelcomeStep {
+                delay: Duration::ZERO,
+                post_id: "65a1b2c3".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_delays_in_each_unit() {
+        assert_eq!(
+            step("30m:post").unwrap().delay,
+            Duration::from_secs(30 * 60)
+        );
+        assert_eq!(
+            step("2h:post").unwrap().delay,
+            Duration::from_secs(2 * 60 * 60)
+        );
+        assert_eq!(
+            step(" 3d : post ").unwrap(),
+            WelcomeStep {
+                delay: Duration::from_secs(3 * 24 * 60 * 60),
+                post_id: "post".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn accepts_zero_delays() {
+        assert_eq!(step("0m:post").unwrap().delay, Duration::ZERO);
+        assert_eq!(step("0d:post").unwrap().delay, Duration::ZERO);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        for s in [
+            "",
+            " ",
+            "3d:",
+            "d:post",
+            "3:post",
+            "3w:post",
+            "-1d:post",
+            "1.5h:post",
+            "3 d:post",
+            "3é:post",
+            "é:post",
+            ":post",
+            "99999999999999999d:post",
+        ] {
+            assert!(step(s).is_err(), "{:?}", s);
+        }
+    }
+}